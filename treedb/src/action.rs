@@ -0,0 +1,130 @@
+use crate::{TreeDb, tree_exists};
+use anyhow::ensure;
+use rusqlite::{OptionalExtension, TransactionBehavior::Immediate};
+use std::collections::BTreeMap;
+
+/// Everything that determines the result of running a command. See [`Action::key`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Action {
+    /// The command line, starting with the program.
+    pub argv: Vec<String>,
+    /// The complete environment. Nothing is inherited.
+    pub env: BTreeMap<String, String>,
+    /// The tree that the command runs in.
+    pub input_tree: blake3::Hash,
+    /// The `/`-separated paths, relative to the input tree, that the command is expected to
+    /// produce. These become the output tree.
+    pub output_paths: Vec<String>,
+}
+
+// Each string is length-prefixed, so that different lists can never hash the same.
+fn hash_strings<'a>(hasher: &mut blake3::Hasher, strings: impl ExactSizeIterator<Item = &'a str>) {
+    hasher.update(&(strings.len() as u64).to_le_bytes());
+    for string in strings {
+        hasher.update(&(string.len() as u64).to_le_bytes());
+        hasher.update(string.as_bytes());
+    }
+}
+
+impl Action {
+    /// The action key, which identifies this action in the action cache. It's a BLAKE3 hash of
+    /// the command line, the environment, the input tree ID, and the output paths. The order of
+    /// `argv` and `output_paths` matters.
+    pub fn key(&self) -> blake3::Hash {
+        let mut hasher = blake3::Hasher::new_derive_key("action_key");
+        hash_strings(&mut hasher, self.argv.iter().map(String::as_str));
+        // The env is sorted, and keys and values alternate.
+        hasher.update(&(self.env.len() as u64).to_le_bytes());
+        for (name, value) in &self.env {
+            hash_strings(&mut hasher, [name.as_str(), value.as_str()].into_iter());
+        }
+        hasher.update(self.input_tree.as_bytes());
+        hash_strings(&mut hasher, self.output_paths.iter().map(String::as_str));
+        hasher.finalize()
+    }
+}
+
+/// The cached result of running an [`Action`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ActionResult {
+    /// A tree containing the output paths that the action produced.
+    pub output_tree: blake3::Hash,
+    pub exit_code: i32,
+    /// Blobs containing everything the action wrote to stdout and stderr.
+    pub stdout: blake3::Hash,
+    pub stderr: blake3::Hash,
+}
+
+// The action cache maps action keys to results. Like refs, the objects that cached results point
+// to are roots for gc(). Use delete_action_result to evict them.
+impl TreeDb {
+    /// Stores `result` for the action with key `action_key`, replacing any previous result. The
+    /// output tree and the stdout and stderr blobs must already exist, and that check is atomic
+    /// with respect to gc().
+    pub fn insert_action_result(
+        &mut self,
+        action_key: &blake3::Hash,
+        result: &ActionResult,
+    ) -> anyhow::Result<()> {
+        let tx = self.conn.transaction_with_behavior(Immediate)?;
+        ensure!(
+            tree_exists(&tx, &result.output_tree)?,
+            "tree {} does not exist",
+            result.output_tree,
+        );
+        for blob_id in [&result.stdout, &result.stderr] {
+            let blob_count: u64 = tx.query_row(
+                "SELECT COUNT(*) FROM blobs WHERE blob_id = ?",
+                (blob_id.as_bytes(),),
+                |row| row.get(0),
+            )?;
+            ensure!(blob_count == 1, "blob {} does not exist", blob_id);
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO actions (action_key, output_tree, exit_code, stdout, stderr)
+             VALUES (?, ?, ?, ?, ?)",
+            (
+                action_key.as_bytes(),
+                result.output_tree.as_bytes(),
+                result.exit_code,
+                result.stdout.as_bytes(),
+                result.stderr.as_bytes(),
+            ),
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Returns the cached result for the action with key `action_key`, or `None` if there isn't
+    /// one.
+    pub fn get_action_result(
+        &self,
+        action_key: &blake3::Hash,
+    ) -> anyhow::Result<Option<ActionResult>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT output_tree, exit_code, stdout, stderr FROM actions WHERE action_key = ?",
+                (action_key.as_bytes(),),
+                |row| {
+                    Ok(ActionResult {
+                        output_tree: row.get::<_, [u8; 32]>(0)?.into(),
+                        exit_code: row.get(1)?,
+                        stdout: row.get::<_, [u8; 32]>(2)?.into(),
+                        stderr: row.get::<_, [u8; 32]>(3)?.into(),
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    /// Deletes the cached result for `action_key`, returning whether it existed. The objects it
+    /// pointed to are left for gc().
+    pub fn delete_action_result(&mut self, action_key: &blake3::Hash) -> anyhow::Result<bool> {
+        let deleted = self.conn.execute(
+            "DELETE FROM actions WHERE action_key = ?",
+            (action_key.as_bytes(),),
+        )?;
+        Ok(deleted > 0)
+    }
+}
@@ -0,0 +1,209 @@
+use crate::bundle::{insert_imported_blobs, read_imported_blob};
+use crate::path::{insert_edit_node, split_path};
+use crate::{NodeType, Tree, TreeDb, TreeEdit};
+use anyhow::{Context, bail, ensure};
+use rusqlite::TransactionBehavior::Immediate;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, prelude::*};
+use tar::{Archive, Builder, EntryType, Header};
+
+// Builds the header fields that are the same for every entry. Timestamps and owners are fixed so
+// that the output only depends on the tree.
+fn deterministic_header(entry_type: EntryType, mode: u32, size: u64) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_mode(mode);
+    header.set_size(size);
+    header.set_mtime(0);
+    header.set_uid(0);
+    header.set_gid(0);
+    header
+}
+
+// Converts a tar path to a `/`-separated tree path, rejecting anything that would escape the
+// root.
+fn tree_path(path: &std::path::Path) -> anyhow::Result<String> {
+    let Some(path_str) = path.to_str() else {
+        bail!("{} is not valid UTF-8", path.to_string_lossy());
+    };
+    let mut components = Vec::new();
+    for component in split_path(path_str) {
+        match component {
+            "." => {}
+            ".." => bail!("{} escapes the archive root", path_str),
+            _ => components.push(component),
+        }
+    }
+    ensure!(
+        !path_str.starts_with('/'),
+        "{} is an absolute path",
+        path_str
+    );
+    Ok(components.join("/"))
+}
+
+impl TreeDb {
+    /// Writes the tree `tree_id` to `writer` as a tar archive. The output is deterministic: the
+    /// same tree always produces byte-identical archives. Entries are sorted, every timestamp and
+    /// owner is zero, files are mode 0755 or 0644 depending on the executable bit, and directories
+    /// are mode 0755.
+    pub fn export_tar(&mut self, tree_id: &blake3::Hash, writer: impl Write) -> anyhow::Result<()> {
+        let mut builder = Builder::new(writer);
+        for entry in self.walk(tree_id)? {
+            let entry = entry?;
+            match entry.node_type {
+                NodeType::Tree => {
+                    let mut header = deterministic_header(EntryType::Directory, 0o755, 0);
+                    builder.append_data(&mut header, format!("{}/", entry.path), io::empty())?;
+                }
+                NodeType::Blob { executable } => {
+                    let reader = self.open_blob(&entry.id)?;
+                    let mode = if executable { 0o755 } else { 0o644 };
+                    let mut header = deterministic_header(EntryType::Regular, mode, reader.len());
+                    builder.append_data(&mut header, &entry.path, reader)?;
+                }
+                NodeType::Symlink => {
+                    let mut target = Vec::new();
+                    self.open_blob(&entry.id)?.read_to_end(&mut target)?;
+                    let Ok(target) = String::from_utf8(target) else {
+                        bail!("target of symlink {} is not valid UTF-8", entry.path);
+                    };
+                    let mut header = deterministic_header(EntryType::Symlink, 0o777, 0);
+                    builder.append_link(&mut header, &entry.path, target)?;
+                }
+            }
+        }
+        builder.into_inner()?.flush()?;
+        Ok(())
+    }
+
+    /// Reads a tar archive from `reader` directly into blobs and trees, and returns the ID of the
+    /// root tree. Regular files, directories, symlinks, and hard links are supported. Ownership
+    /// and timestamps are ignored, and files are executable if any execute bit is set.
+    ///
+    /// Entries are applied in the order they appear, so if a path appears more than once, the
+    /// last entry wins. A directory entry only replaces what's at its path if that isn't already
+    /// a directory, so it doesn't clear out files that came before it. The whole archive is read
+    /// before taking the write lock, and then everything is inserted in a single transaction.
+    pub fn import_tar(&mut self, reader: impl Read) -> anyhow::Result<blake3::Hash> {
+        let mut archive = Archive::new(reader);
+        let mut blobs = Vec::new();
+        let mut edits = Vec::new();
+        // Paths that are directories so far, explicitly or as parents of other entries.
+        let mut dirs: BTreeSet<String> = BTreeSet::new();
+        // For resolving hard links. Files leave this when something replaces them.
+        let mut files: BTreeMap<String, (blake3::Hash, NodeType)> = BTreeMap::new();
+        let empty_id = Tree::new().id();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = tree_path(&entry.path()?)?;
+            let entry_type = entry.header().entry_type();
+            if entry_type.is_dir() {
+                if !path.is_empty() && !dirs.contains(&path) {
+                    add_dir(&mut dirs, &mut files, &path);
+                    edits.push(TreeEdit::Set {
+                        path,
+                        id: empty_id,
+                        node_type: NodeType::Tree,
+                    });
+                }
+                continue;
+            }
+            ensure!(!path.is_empty(), "archive entry has an empty path");
+            let (id, node_type) = match entry_type {
+                EntryType::Regular | EntryType::Continuous => {
+                    let executable = entry.header().mode()? & 0o111 != 0;
+                    let len = entry.size();
+                    let blob = read_imported_blob(&mut entry, len, &self.blobs_dir)
+                        .with_context(|| format!("failed to read {} from archive", path))?;
+                    let id = blob.id();
+                    blobs.push(blob);
+                    (id, NodeType::Blob { executable })
+                }
+                EntryType::Symlink => {
+                    let Some(target) = entry.link_name_bytes() else {
+                        bail!("symlink {} has no target", path);
+                    };
+                    let blob = read_imported_blob(&*target, target.len() as u64, &self.blobs_dir)?;
+                    let id = blob.id();
+                    blobs.push(blob);
+                    (id, NodeType::Symlink)
+                }
+                EntryType::Link => {
+                    let Some(target) = entry.link_name()? else {
+                        bail!("hard link {} has no target", path);
+                    };
+                    let target = tree_path(&target)?;
+                    let Some(&file) = files.get(&target) else {
+                        bail!("hard link {} points to unknown file {}", path, target);
+                    };
+                    file
+                }
+                other => bail!("{} has unsupported type {:?}", path, other),
+            };
+            // A file replaces any directory at its path, and everything in it.
+            let prefix = format!("{}/", path);
+            let replaced: Vec<String> = dirs
+                .range(path.clone()..)
+                .take_while(|dir| **dir == path || dir.starts_with(&prefix))
+                .cloned()
+                .collect();
+            for dir in replaced {
+                dirs.remove(&dir);
+            }
+            let replaced: Vec<String> = files
+                .range(prefix.clone()..)
+                .take_while(|(file, _)| file.starts_with(&prefix))
+                .map(|(file, _)| file.clone())
+                .collect();
+            for file in replaced {
+                files.remove(&file);
+            }
+            if let Some((parent, _)) = path.rsplit_once('/') {
+                add_dir(&mut dirs, &mut files, parent);
+            }
+            files.insert(path.clone(), (id, node_type));
+            edits.push(TreeEdit::Set {
+                path,
+                id,
+                node_type,
+            });
+        }
+        let root = self.apply_edits(&empty_id, &edits)?;
+
+        // Deferred transactions are vulnerable to BUSY errors if there are concurrent writers.
+        // See: https://fractaledmind.github.io/2024/04/15/sqlite-on-rails-the-how-and-why-of-optimal-performance/
+        let tx = self.conn.transaction_with_behavior(Immediate)?;
+        let persisted_paths =
+            insert_imported_blobs(&tx, &self.blobs_dir, self.compression, self.chunking, blobs)?;
+        let (root_id, _) = insert_edit_node(&tx, &root)?;
+
+        // Commit!
+        tx.commit()?;
+
+        // Finally, make the new files read-only.
+        for path in persisted_paths {
+            let mut permissions = fs::metadata(&path)?.permissions();
+            permissions.set_readonly(true);
+            fs::set_permissions(&path, permissions)?;
+        }
+        Ok(root_id)
+    }
+}
+
+// Records that `path` and all of its parents are directories, which replaces any files there.
+fn add_dir(
+    dirs: &mut BTreeSet<String>,
+    files: &mut BTreeMap<String, (blake3::Hash, NodeType)>,
+    path: &str,
+) {
+    let mut end = path.len();
+    while dirs.insert(path[..end].to_string()) {
+        files.remove(&path[..end]);
+        match path[..end].rfind('/') {
+            Some(slash) => end = slash,
+            None => break,
+        }
+    }
+}
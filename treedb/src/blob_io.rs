@@ -0,0 +1,299 @@
+use crate::chunk::{self, ChunkedReader};
+use crate::compress::{self, Codec, SeekableDecoder};
+use crate::gc;
+use crate::outboard::{self, OutboardEncoder};
+use crate::{LARGE_BLOB_THRESHOLD, TreeDb};
+use anyhow::{Context, bail};
+use rusqlite::blob::Blob;
+use rusqlite::{DatabaseName, OptionalExtension, TransactionBehavior::Immediate};
+use std::fs::{self, File};
+use std::io::{self, SeekFrom, prelude::*};
+use tempfile::NamedTempFile;
+
+/// A streaming reader for a blob, returned by [`TreeDb::open_blob`].
+pub struct BlobReader<'a> {
+    inner: BlobReaderInner<'a>,
+    len: u64,
+}
+
+enum BlobReaderInner<'a> {
+    // Small blobs are read with SQLite's incremental blob I/O.
+    Small(Blob<'a>),
+    // Large blobs are read from the blobs dir.
+    Large(File),
+    // Compressed small blobs are small enough to decompress up front.
+    Decompressed(io::Cursor<Vec<u8>>),
+    // Compressed large blobs are decompressed as they're read.
+    LargeCompressed(SeekableDecoder),
+    // Chunked blobs are read from the chunks table one chunk at a time.
+    Chunked(ChunkedReader<'a>),
+}
+
+impl BlobReader<'_> {
+    /// The total size of the blob in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl std::fmt::Debug for BlobReader<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.inner {
+            BlobReaderInner::Small(_) => "Small",
+            BlobReaderInner::Large(_) => "Large",
+            BlobReaderInner::Decompressed(_) => "Decompressed",
+            BlobReaderInner::LargeCompressed(_) => "LargeCompressed",
+            BlobReaderInner::Chunked(_) => "Chunked",
+        };
+        f.debug_struct("BlobReader")
+            .field("kind", &kind)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl Read for BlobReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.inner {
+            BlobReaderInner::Small(blob) => blob.read(buf),
+            BlobReaderInner::Large(file) => file.read(buf),
+            BlobReaderInner::Decompressed(cursor) => cursor.read(buf),
+            BlobReaderInner::LargeCompressed(decoder) => decoder.read(buf),
+            BlobReaderInner::Chunked(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl Seek for BlobReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match &mut self.inner {
+            BlobReaderInner::Small(blob) => blob.seek(pos),
+            BlobReaderInner::Large(file) => file.seek(pos),
+            BlobReaderInner::Decompressed(cursor) => cursor.seek(pos),
+            BlobReaderInner::LargeCompressed(decoder) => decoder.seek(pos),
+            BlobReaderInner::Chunked(reader) => reader.seek(pos),
+        }
+    }
+}
+
+/// A streaming writer for a blob of unknown length, returned by [`TreeDb::blob_writer`]. Call
+/// [`finish`](Self::finish) to insert the blob. Dropping the writer without finishing it discards
+/// the data. After any write fails, the writer is poisoned: later writes and `finish` return
+/// errors, since the data it holds may be incomplete.
+pub struct BlobWriter<'a> {
+    db: &'a mut TreeDb,
+    // Builds the outboard for read_range() as the data comes in, along with the blob ID.
+    encoder: OutboardEncoder,
+    // Data is buffered in memory until it reaches LARGE_BLOB_THRESHOLD, and then it spills to a
+    // tempfile in the blobs dir. The tempfile's name never looks like a blob ID, so gc() leaves
+    // it alone.
+    buffer: Vec<u8>,
+    spill_file: Option<NamedTempFile>,
+    poisoned: bool,
+}
+
+impl std::fmt::Debug for BlobWriter<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlobWriter")
+            .field("buffered", &self.buffer.len())
+            .field("spill_file", &self.spill_file)
+            .field("poisoned", &self.poisoned)
+            .finish()
+    }
+}
+
+impl Write for BlobWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.poisoned {
+            return Err(io::Error::other(
+                "an earlier write to this BlobWriter failed",
+            ));
+        }
+        // The fallible writes happen first, so that the encoder and the buffer only see data that
+        // made it. A failed write to the tempfile may have written part of `buf`, though, so any
+        // error poisons the writer.
+        let result = self.write_data(buf);
+        self.poisoned = result.is_err();
+        result?;
+        self.encoder.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.spill_file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl BlobWriter<'_> {
+    fn write_data(&mut self, buf: &[u8]) -> io::Result<()> {
+        if let Some(file) = &mut self.spill_file {
+            file.write_all(buf)?;
+        } else if self.buffer.len() + buf.len() >= LARGE_BLOB_THRESHOLD {
+            let mut file = tempfile::Builder::new()
+                .prefix("tmp-")
+                .tempfile_in(&self.db.blobs_dir)?;
+            file.write_all(&self.buffer)?;
+            file.write_all(buf)?;
+            self.buffer = Vec::new();
+            self.spill_file = Some(file);
+        } else {
+            self.buffer.extend_from_slice(buf);
+        }
+        Ok(())
+    }
+
+    /// Inserts the blob and returns its ID. Like [`TreeDb::insert_blob`], this short-circuits if
+    /// the blob already exists.
+    pub fn finish(self) -> anyhow::Result<blake3::Hash> {
+        if self.poisoned {
+            bail!("an earlier write to this BlobWriter failed");
+        }
+        let Some(mut spill_file) = self.spill_file else {
+            // Small blobs go in the blobs table.
+            return self.db.insert_blob(&self.buffer);
+        };
+        spill_file.flush()?;
+        let (blob_id, outboard) = self.encoder.finalize();
+        let blob_path = self.db.blob_path(&blob_id);
+        let compression = self.db.compression;
+        let chunking = self.db.chunking;
+
+        // Deferred transactions are vulnerable to BUSY errors if there are concurrent writers.
+        // See: https://fractaledmind.github.io/2024/04/15/sqlite-on-rails-the-how-and-why-of-optimal-performance/
+        let tx = self.db.conn.transaction_with_behavior(Immediate)?;
+
+        // Short-circuit if this blob already exists. The tempfile is deleted when it's dropped.
+        let exists: u64 = tx.query_row(
+            "SELECT COUNT(*) FROM blobs WHERE blob_id = ?",
+            (blob_id.as_bytes(),),
+            |row| row.get(0),
+        )?;
+        assert!(exists <= 1);
+        gc::mark_recent(&tx, &blob_id)?;
+        if exists == 1 {
+            tx.commit()?;
+            return Ok(blob_id);
+        }
+
+        // The outboard was built as the data was written.
+        outboard::insert_outboard(&tx, &blob_id, &outboard)?;
+
+        // Renaming silently replaces any leftover file from an insert that failed before
+        // committing. If chunking or compression is enabled, read the tempfile into chunks or
+        // compress it instead, and let it be deleted when it's dropped.
+        let codec = if chunking {
+            spill_file.rewind()?;
+            chunk::insert_chunks(&tx, &blob_id, spill_file.as_file(), compression)?;
+            Codec::Chunked
+        } else if compression.is_some() {
+            compress::store_file(spill_file.path(), &blob_path, compression)?
+        } else {
+            spill_file.persist(&blob_path).with_context(|| {
+                format!("failed to move tempfile to {}", blob_path.to_string_lossy())
+            })?;
+            Codec::None
+        };
+
+        // NULL data means the data is in the blobs dir. Note that this write won't be observable
+        // to concurrent readers until we commit.
+        tx.execute(
+            "INSERT INTO blobs (blob_id, data, codec) VALUES (?, NULL, ?)",
+            (blob_id.as_bytes(), codec.to_column()),
+        )?;
+
+        // Commit!
+        tx.commit()?;
+
+        // Finally, make the file read-only.
+        if codec == Codec::Chunked {
+            return Ok(blob_id);
+        }
+        let mut permissions = fs::metadata(&blob_path)?.permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&blob_path, permissions)?;
+        Ok(blob_id)
+    }
+}
+
+impl TreeDb {
+    /// Returns a [`BlobWriter`] for inserting a blob incrementally, when its length isn't known up
+    /// front.
+    pub fn blob_writer(&mut self) -> BlobWriter<'_> {
+        BlobWriter {
+            db: self,
+            encoder: OutboardEncoder::new(),
+            buffer: Vec::new(),
+            spill_file: None,
+            poisoned: false,
+        }
+    }
+
+    /// Returns a `Read + Seek` handle to the blob, without reading it into memory, or an error if
+    /// the `blob_id` doesn't exist.
+    pub fn open_blob(&self, blob_id: &blake3::Hash) -> anyhow::Result<BlobReader<'_>> {
+        // If there is no row, the blob doesn't exist. If there is a row but it has NULL data, the
+        // data is in the blobs dir.
+        let row: Option<(i64, bool, u8)> = self
+            .conn
+            .query_row(
+                "SELECT rowid, data IS NULL, codec FROM blobs WHERE blob_id = ?",
+                (blob_id.as_bytes(),),
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        let Some((rowid, is_large, codec)) = row else {
+            bail!("blob {} doesn't exist", blob_id);
+        };
+        let codec = Codec::from_column(codec)?;
+        let inner = match (is_large, codec) {
+            // Data is in the blobs table.
+            (false, Codec::None) => BlobReaderInner::Small(self.conn.blob_open(
+                DatabaseName::Main,
+                "blobs",
+                "data",
+                rowid,
+                true,
+            )?),
+            (false, codec) => {
+                let data: Vec<u8> = self.conn.query_row(
+                    "SELECT data FROM blobs WHERE rowid = ?",
+                    (rowid,),
+                    |row| row.get(0),
+                )?;
+                BlobReaderInner::Decompressed(io::Cursor::new(compress::decode_bytes(data, codec)?))
+            }
+            // Data is in the chunks table.
+            (true, Codec::Chunked) => {
+                BlobReaderInner::Chunked(ChunkedReader::new(&self.conn, blob_id)?)
+            }
+            // Data is in the blobs dir.
+            (true, codec) => {
+                let path = self.blob_path(blob_id);
+                let mut file = File::open(&path)
+                    .with_context(|| format!("failed to open {}", path.to_string_lossy()))?;
+                match codec {
+                    Codec::None => BlobReaderInner::Large(file),
+                    codec => {
+                        let len = compress::decoded_len(&mut file, codec)?;
+                        BlobReaderInner::LargeCompressed(SeekableDecoder::new(file, len)?)
+                    }
+                }
+            }
+        };
+        let len = match &inner {
+            BlobReaderInner::Small(blob) => blob.len() as u64,
+            BlobReaderInner::Large(file) => file.metadata()?.len(),
+            BlobReaderInner::Decompressed(cursor) => cursor.get_ref().len() as u64,
+            BlobReaderInner::LargeCompressed(decoder) => decoder.len(),
+            BlobReaderInner::Chunked(reader) => reader.len(),
+        };
+        Ok(BlobReader { inner, len })
+    }
+}
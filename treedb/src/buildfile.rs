@@ -0,0 +1,335 @@
+use crate::diff::join_path;
+use crate::dir::{is_executable, read_symlink};
+use crate::path::split_path;
+use crate::{NodeType, Target, TargetInput, Tree, TreeDb, TreeEdit};
+use anyhow::{Context, anyhow, bail};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+use toml::Spanned;
+
+const BUILD_FILE_NAME: &str = "BUILD.toml";
+
+// The contents of a build file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BuildFileToml {
+    #[serde(default)]
+    target: BTreeMap<String, Spanned<TargetTable>>,
+}
+
+// A `[target.NAME]` table. The spans are kept for error messages.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TargetTable {
+    command: Spanned<String>,
+    outputs: Spanned<Vec<String>>,
+    sources: Option<Spanned<Vec<String>>>,
+    deps: Option<Spanned<Vec<String>>>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+}
+
+// Matches one path component against a glob component, where `*` matches any run of characters
+// and `?` matches one.
+fn match_component(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|i| match_component(rest, &name[i..])),
+        Some(('?', rest)) => !name.is_empty() && match_component(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && match_component(rest, &name[1..]),
+    }
+}
+
+fn match_glob(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|i| match_glob(rest, &path[i..])),
+        Some((component, rest)) => {
+            let Some((name, path_rest)) = path.split_first() else {
+                return false;
+            };
+            let pattern: Vec<char> = component.chars().collect();
+            let name: Vec<char> = name.chars().collect();
+            match_component(&pattern, &name) && match_glob(rest, path_rest)
+        }
+    }
+}
+
+// Returns the `/`-separated paths of all the files and symlinks under `dir`, relative to it.
+// Hidden directories and cache directories, like Cargo's target directory, are skipped.
+fn list_files(dir: &Path, prefix: &str, files: &mut Vec<String>) -> anyhow::Result<()> {
+    let entries = fs::read_dir(dir)
+        .with_context(|| format!("failed to read directory {}", dir.to_string_lossy()))?;
+    for entry in entries {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            bail!("non-UTF-8 filename in {}", dir.to_string_lossy());
+        };
+        let path = join_path(prefix, &name);
+        if entry.file_type()?.is_dir() {
+            if !name.starts_with('.') && !skip_dir(&entry.path()) {
+                list_files(&entry.path(), &path, files)?;
+            }
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Cache directories are marked with a CACHEDIR.TAG file. See https://bford.info/cachedir/.
+fn skip_dir(dir: &Path) -> bool {
+    dir.join("CACHEDIR.TAG").exists()
+}
+
+// Returns the workspace-relative directories under `root` that contain a build file, sorted.
+fn find_build_dirs(root: &Path) -> anyhow::Result<Vec<String>> {
+    let mut files = Vec::new();
+    list_files(root, "", &mut files)?;
+    let mut dirs: Vec<String> = files
+        .iter()
+        .filter_map(|file| match file.rsplit_once('/') {
+            Some((dir, BUILD_FILE_NAME)) => Some(dir.to_string()),
+            None if file == BUILD_FILE_NAME => Some(String::new()),
+            _ => None,
+        })
+        .collect();
+    dirs.sort();
+    Ok(dirs)
+}
+
+// Quotes a word for /bin/sh, unless it's made entirely of characters that are safe as they are.
+fn shell_quote(word: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-./+=:,@%".contains(c);
+    if !word.is_empty() && word.chars().all(safe) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+fn expand_command(
+    template: &str,
+    dir: &str,
+    sources: &[String],
+    outputs: &[String],
+) -> Result<String, String> {
+    let join = |paths: &[String]| {
+        paths
+            .iter()
+            .map(|path| shell_quote(path))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let mut command = String::new();
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        command.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(after) = rest.strip_prefix("{{") {
+            command.push('{');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("}}") {
+            command.push('}');
+            rest = after;
+        } else if rest.starts_with('}') {
+            return Err("unmatched '}' in command".into());
+        } else {
+            let Some(end) = rest.find('}') else {
+                return Err("unmatched '{' in command".into());
+            };
+            match &rest[1..end] {
+                "dir" => command.push_str(&shell_quote(if dir.is_empty() { "." } else { dir })),
+                "sources" => command.push_str(&join(sources)),
+                "outputs" => command.push_str(&join(outputs)),
+                name => return Err(format!("unknown placeholder {{{}}} in command", name)),
+            }
+            rest = &rest[end + 1..];
+        }
+    }
+    command.push_str(rest);
+    Ok(command)
+}
+
+// A build file, and where it came from.
+struct BuildFile {
+    // Relative to the workspace root, and empty at the root.
+    dir: String,
+    file: String,
+    text: String,
+}
+
+impl BuildFile {
+    // Reports an error at the line where `span` starts.
+    fn error(&self, span: Range<usize>, message: impl std::fmt::Display) -> anyhow::Error {
+        let line = self.text[..span.start].matches('\n').count() + 1;
+        anyhow!("{}:{}: {}", self.file, line, message)
+    }
+
+    fn label(&self, name: &str) -> String {
+        format!("{}:{}", self.dir, name)
+    }
+
+    // Returns the targets in the order they appear in the file.
+    fn parse(&self) -> anyhow::Result<Vec<(String, TargetTable)>> {
+        let parsed: BuildFileToml = toml::from_str(&self.text)
+            .map_err(|e| self.error(e.span().unwrap_or(0..0), e.message()))?;
+        let mut targets: Vec<_> = parsed.target.into_iter().collect();
+        targets.sort_by_key(|(_, table)| table.span().start);
+        Ok(targets
+            .into_iter()
+            .map(|(name, table)| (name, table.into_inner()))
+            .collect())
+    }
+}
+
+impl TreeDb {
+    /// Loads the targets declared in every `BUILD.toml` file under the workspace `root`, in a form
+    /// ready for [`build`](TreeDb::build), and inserts their source files. Errors in build files
+    /// are reported with the file and line.
+    ///
+    /// Build files are TOML. Each `[target.NAME]` table becomes a [`Target`] named `DIR:NAME`,
+    /// where `DIR` is the directory relative to `root` (empty at the root). Unknown keys are
+    /// errors. The keys are:
+    ///
+    /// - `command` (required): a shell command, run by `/bin/sh -c` from the root of the sandbox.
+    ///   `{dir}`, `{sources}`, and `{outputs}` expand to the target's directory, its source files,
+    ///   and its outputs, quoted for the shell. `{{` and `}}` are literal braces. Long commands
+    ///   can be split with a multi-line string and a `\` at the end of each line.
+    /// - `outputs` (required): the files or directories the command produces, relative to `DIR`.
+    /// - `sources`: globs, relative to `DIR`, for the files the command reads. `*` and `?` match
+    ///   within one path component, and `**` matches any number of components. Each glob must
+    ///   match at least one file.
+    /// - `deps`: the targets whose outputs the command reads, as `:NAME` in the same file or
+    ///   `DIR:NAME`.
+    /// - `env`: an inline table with the command's environment, which is otherwise empty.
+    ///
+    /// The sandbox has the same layout as the workspace. Sources and the outputs of dependencies
+    /// all appear at their paths relative to `root`. Hidden directories and cache directories
+    /// marked with `CACHEDIR.TAG`, like Cargo's `target`, are skipped, both when looking for
+    /// build files and when matching sources.
+    pub fn load_build_files(&mut self, root: &Path) -> anyhow::Result<Vec<Target>> {
+        let mut build_files = Vec::new();
+        for dir in find_build_dirs(root)? {
+            let file = join_path(&dir, BUILD_FILE_NAME);
+            let text = fs::read_to_string(root.join(&file))
+                .with_context(|| format!("failed to read {}", file))?;
+            let build_file = BuildFile { dir, file, text };
+            let tables = build_file.parse()?;
+            build_files.push((build_file, tables));
+        }
+        let labels: HashSet<String> = build_files
+            .iter()
+            .flat_map(|(build_file, tables)| tables.iter().map(|(name, _)| build_file.label(name)))
+            .collect();
+
+        let mut targets = Vec::new();
+        for (build_file, tables) in build_files {
+            let mut dir_files = Vec::new();
+            list_files(&root.join(&build_file.dir), "", &mut dir_files)?;
+            for (name, table) in tables {
+                targets.push(self.load_target(
+                    root,
+                    &build_file,
+                    &name,
+                    table,
+                    &labels,
+                    &dir_files,
+                )?);
+            }
+        }
+        Ok(targets)
+    }
+
+    fn load_target(
+        &mut self,
+        root: &Path,
+        build_file: &BuildFile,
+        name: &str,
+        table: TargetTable,
+        labels: &HashSet<String>,
+        dir_files: &[String],
+    ) -> anyhow::Result<Target> {
+        let dir = &build_file.dir;
+        let outputs_span = table.outputs.span();
+        if table.outputs.get_ref().is_empty() {
+            let message = format!("target {:?} has no outputs", name);
+            return Err(build_file.error(outputs_span, message));
+        }
+        let mut output_paths = Vec::new();
+        for output in table.outputs.get_ref() {
+            let valid = !output.starts_with('/')
+                && split_path(output).next().is_some()
+                && split_path(output).all(|component| component != "." && component != "..");
+            if !valid {
+                let message = format!("output {:?} isn't a normalized relative path", output);
+                return Err(build_file.error(outputs_span.clone(), message));
+            }
+            output_paths.push(join_path(dir, output));
+        }
+
+        let mut sources = BTreeSet::new();
+        for glob in table.sources.iter().flat_map(|globs| globs.get_ref()) {
+            let pattern: Vec<&str> = split_path(glob).collect();
+            let mut matched = false;
+            for path in dir_files {
+                if match_glob(&pattern, &path.split('/').collect::<Vec<_>>()) {
+                    sources.insert(join_path(dir, path));
+                    matched = true;
+                }
+            }
+            if !matched {
+                let message = format!("{:?} doesn't match any files", glob);
+                let span = table.sources.as_ref().expect("sources").span();
+                return Err(build_file.error(span, message));
+            }
+        }
+        let empty_id = self.insert_tree(&Tree::new())?;
+        let mut edits = Vec::new();
+        for source in &sources {
+            let path = root.join(source);
+            let metadata = fs::symlink_metadata(&path)?;
+            let (id, node_type) = if metadata.is_symlink() {
+                (self.insert_blob(&read_symlink(&path)?)?, NodeType::Symlink)
+            } else {
+                let executable = is_executable(&metadata);
+                (self.insert_file(&path)?, NodeType::Blob { executable })
+            };
+            edits.push(TreeEdit::Set {
+                path: source.clone(),
+                id,
+                node_type,
+            });
+        }
+
+        let mut inputs = vec![(
+            String::new(),
+            TargetInput::Tree(self.edit_tree(&empty_id, &edits)?),
+        )];
+        for dep in table.deps.iter().flat_map(|deps| deps.get_ref()) {
+            let label = match dep.strip_prefix(':') {
+                Some(name) => build_file.label(name),
+                None => dep.clone(),
+            };
+            if !labels.contains(&label) {
+                let span = table.deps.as_ref().expect("deps").span();
+                return Err(build_file.error(span, format!("unknown target {:?}", dep)));
+            }
+            inputs.push((String::new(), TargetInput::Target(label)));
+        }
+
+        let sources: Vec<String> = sources.into_iter().collect();
+        let command = expand_command(table.command.get_ref(), dir, &sources, &output_paths)
+            .map_err(|message| build_file.error(table.command.span(), message))?;
+        Ok(Target {
+            name: build_file.label(name),
+            inputs,
+            argv: vec!["/bin/sh".into(), "-c".into(), command],
+            env: table.env,
+            output_paths,
+        })
+    }
+}
@@ -0,0 +1,353 @@
+use crate::chunk;
+use crate::compress::{self, Codec};
+use crate::gc;
+use crate::outboard::{self, OutboardEncoder};
+use crate::{LARGE_BLOB_THRESHOLD, NodeType, Tree, TreeDb, insert_tree_rows, tree_exists};
+use anyhow::{Context, bail, ensure};
+use rusqlite::TransactionBehavior::Immediate;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use tempfile::TempPath;
+
+const MAGIC: &[u8; 8] = b"TDBUNDLE";
+const VERSION: u32 = 1;
+
+const RECORD_END: u8 = 0;
+const RECORD_BLOB: u8 = 1;
+const RECORD_TREE: u8 = 2;
+
+// The same values as the node type bytes in Tree::id().
+fn node_type_bytes(node_type: NodeType) -> [u8; 2] {
+    match node_type {
+        NodeType::Blob { executable: false } => [0, 0],
+        NodeType::Blob { executable: true } => [0, 1],
+        NodeType::Tree => [1, 0],
+        NodeType::Symlink => [2, 0],
+    }
+}
+
+fn parse_node_type_bytes(bytes: [u8; 2]) -> anyhow::Result<NodeType> {
+    Ok(match bytes {
+        [0, 0] => NodeType::Blob { executable: false },
+        [0, 1] => NodeType::Blob { executable: true },
+        [1, 0] => NodeType::Tree,
+        [2, 0] => NodeType::Symlink,
+        _ => bail!("unknown node type: {:?}", bytes),
+    })
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(read_array(reader)?))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    Ok(u64::from_le_bytes(read_array(reader)?))
+}
+
+// A blob that's been read and hashed, but not inserted yet. Large blobs keep only the path of
+// their tempfile, so that an import with many of them doesn't hold a file descriptor for each one,
+// along with the outboard that was built while reading them.
+pub(crate) enum ImportedBlob {
+    Small(blake3::Hash, Vec<u8>),
+    Large(blake3::Hash, TempPath, Vec<u8>),
+}
+
+impl ImportedBlob {
+    pub(crate) fn id(&self) -> blake3::Hash {
+        match self {
+            ImportedBlob::Small(blob_id, _) | ImportedBlob::Large(blob_id, ..) => *blob_id,
+        }
+    }
+}
+
+// Reads and hashes a blob of exactly `len` bytes, without taking the write lock. Large blobs are
+// streamed to tempfiles in the blobs dir. See BlobWriter for why those are safe from gc().
+pub(crate) fn read_imported_blob(
+    reader: impl Read,
+    len: u64,
+    blobs_dir: &Path,
+) -> anyhow::Result<ImportedBlob> {
+    let mut limited = reader.take(len);
+    let blob = if len < LARGE_BLOB_THRESHOLD as u64 {
+        let mut data = Vec::with_capacity(len as usize);
+        limited.read_to_end(&mut data)?;
+        ImportedBlob::Small(blake3::hash(&data), data)
+    } else {
+        let mut file = tempfile::Builder::new()
+            .prefix("tmp-")
+            .tempfile_in(blobs_dir)?;
+        let mut encoder = OutboardEncoder::new();
+        io::copy(&mut limited, &mut HashingWriter(&mut encoder, &mut file))?;
+        let (blob_id, outboard) = encoder.finalize();
+        ImportedBlob::Large(blob_id, file.into_temp_path(), outboard)
+    };
+    ensure!(limited.limit() == 0, "unexpected end of input");
+    Ok(blob)
+}
+
+// Inserts blobs that were read with read_imported_blob, skipping any that already exist. The
+// caller is responsible for the transaction, and for making the returned blob files read-only
+// after it commits.
+pub(crate) fn insert_imported_blobs(
+    tx: &rusqlite::Connection,
+    blobs_dir: &Path,
+    compression: Option<i32>,
+    chunking: bool,
+    blobs: Vec<ImportedBlob>,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut persisted_paths = Vec::new();
+    for blob in blobs {
+        gc::mark_recent(tx, &blob.id())?;
+        match blob {
+            ImportedBlob::Small(blob_id, data) => {
+                let (data, codec) = compress::encode_bytes(&data, compression)?;
+                tx.execute(
+                    "INSERT OR IGNORE INTO blobs (blob_id, data, codec) VALUES (?, ?, ?)",
+                    (blob_id.as_bytes(), &data, codec.to_column()),
+                )?;
+            }
+            ImportedBlob::Large(blob_id, path, outboard) => {
+                let exists: u64 = tx.query_row(
+                    "SELECT COUNT(*) FROM blobs WHERE blob_id = ?",
+                    (blob_id.as_bytes(),),
+                    |row| row.get(0),
+                )?;
+                if exists == 1 {
+                    continue;
+                }
+                outboard::insert_outboard(tx, &blob_id, &outboard)?;
+                // As in BlobWriter, chunking and compressing read the tempfile instead of
+                // renaming it.
+                let blob_path = blobs_dir.join(blob_id.to_hex().as_str());
+                let codec = if chunking {
+                    let reader = fs::File::open(&path)?;
+                    chunk::insert_chunks(tx, &blob_id, reader, compression)?;
+                    Codec::Chunked
+                } else if compression.is_some() {
+                    compress::store_file(&path, &blob_path, compression)?
+                } else {
+                    path.persist(&blob_path)?;
+                    Codec::None
+                };
+                tx.execute(
+                    "INSERT INTO blobs (blob_id, data, codec) VALUES (?, NULL, ?)",
+                    (blob_id.as_bytes(), codec.to_column()),
+                )?;
+                if codec != Codec::Chunked {
+                    persisted_paths.push(blob_path);
+                }
+            }
+        }
+    }
+    Ok(persisted_paths)
+}
+
+impl TreeDb {
+    /// Writes the trees `root_ids` and everything they reference to `writer`, as a single bundle
+    /// that [`import_bundle`](Self::import_bundle) can read.
+    ///
+    /// The format (version 1) is, with all integers little-endian:
+    ///
+    /// - The magic bytes `TDBUNDLE`, followed by the version as a `u32`.
+    /// - The number of roots as a `u32`, followed by each root ID (32 bytes).
+    /// - Any number of records, each starting with a one-byte tag:
+    ///   - `1`, a blob: its ID (32 bytes), its length as a `u64`, and its contents.
+    ///   - `2`, a tree: its ID (32 bytes), its number of children as a `u32`, and for each child
+    ///     its node type (2 bytes, the same as in [`Tree::id`]), its ID (32 bytes), the length of
+    ///     its name as a `u32`, and its name.
+    /// - A single `0` byte to end the bundle.
+    ///
+    /// All the blobs come first, and every tree comes after its subtrees. Each object appears
+    /// once.
+    pub fn export_bundle(
+        &mut self,
+        root_ids: &[blake3::Hash],
+        mut writer: impl Write,
+    ) -> anyhow::Result<()> {
+        // Collect the closure. Trees are in post-order.
+        let mut trees = Vec::new();
+        let mut blobs = Vec::new();
+        let mut visited = HashSet::new();
+        let mut stack: Vec<(blake3::Hash, bool)> =
+            root_ids.iter().rev().map(|id| (*id, false)).collect();
+        let mut pending_trees = Vec::new();
+        while let Some((tree_id, children_done)) = stack.pop() {
+            if children_done {
+                trees.push(pending_trees.pop().expect("pending tree"));
+                continue;
+            }
+            if !visited.insert(tree_id) {
+                continue;
+            }
+            let Some(tree) = self.get_tree(&tree_id)? else {
+                bail!("tree {} doesn't exist", tree_id);
+            };
+            stack.push((tree_id, true));
+            for child in tree.iter() {
+                match child.node_type {
+                    NodeType::Blob { .. } | NodeType::Symlink => {
+                        if visited.insert(*child.id) {
+                            blobs.push(*child.id);
+                        }
+                    }
+                    NodeType::Tree => {
+                        if !visited.contains(child.id) {
+                            stack.push((*child.id, false));
+                        }
+                    }
+                }
+            }
+            pending_trees.push(tree);
+        }
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&(root_ids.len() as u32).to_le_bytes())?;
+        for root_id in root_ids {
+            writer.write_all(root_id.as_bytes())?;
+        }
+        for blob_id in &blobs {
+            let mut reader = self.open_blob(blob_id)?;
+            writer.write_all(&[RECORD_BLOB])?;
+            writer.write_all(blob_id.as_bytes())?;
+            writer.write_all(&reader.len().to_le_bytes())?;
+            let copied = io::copy(&mut reader, &mut writer)?;
+            ensure!(copied == reader.len(), "blob {} changed size", blob_id);
+        }
+        for tree in &trees {
+            writer.write_all(&[RECORD_TREE])?;
+            writer.write_all(tree.id().as_bytes())?;
+            writer.write_all(&(tree.len() as u32).to_le_bytes())?;
+            for child in tree.iter() {
+                writer.write_all(&node_type_bytes(child.node_type))?;
+                writer.write_all(child.id.as_bytes())?;
+                writer.write_all(&(child.name.len() as u32).to_le_bytes())?;
+                writer.write_all(child.name.as_bytes())?;
+            }
+        }
+        writer.write_all(&[RECORD_END])?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Reads a bundle written by [`export_bundle`](Self::export_bundle) and returns its root IDs.
+    /// Every blob and tree is verified against its ID, and nothing is committed unless the whole
+    /// bundle is valid and every object it references is either in the bundle or already in this
+    /// database.
+    pub fn import_bundle(&mut self, mut reader: impl Read) -> anyhow::Result<Vec<blake3::Hash>> {
+        // Read and verify everything before taking the write lock. Large blobs are streamed to
+        // tempfiles in the blobs dir.
+        let magic: [u8; 8] = read_array(&mut reader).context("failed to read bundle header")?;
+        ensure!(&magic == MAGIC, "not a bundle");
+        let version = read_u32(&mut reader)?;
+        ensure!(version == VERSION, "unsupported bundle version {}", version);
+        let root_count = read_u32(&mut reader)?;
+        let mut root_ids = Vec::new();
+        for _ in 0..root_count {
+            root_ids.push(blake3::Hash::from(read_array::<32>(&mut reader)?));
+        }
+        let mut blobs = Vec::new();
+        let mut trees = Vec::new();
+        loop {
+            let [tag] = read_array(&mut reader).context("truncated bundle")?;
+            match tag {
+                RECORD_END => break,
+                RECORD_BLOB => {
+                    let blob_id = blake3::Hash::from(read_array::<32>(&mut reader)?);
+                    let len = read_u64(&mut reader)?;
+                    let blob = read_imported_blob(&mut reader, len, &self.blobs_dir)
+                        .context("truncated bundle")?;
+                    ensure!(
+                        blob.id() == blob_id,
+                        "blob {} doesn't match its contents",
+                        blob_id,
+                    );
+                    blobs.push(blob);
+                }
+                RECORD_TREE => {
+                    let tree_id = blake3::Hash::from(read_array::<32>(&mut reader)?);
+                    let child_count = read_u32(&mut reader)?;
+                    let mut tree = Tree::new();
+                    for _ in 0..child_count {
+                        let node_type = parse_node_type_bytes(read_array(&mut reader)?)?;
+                        let child_id = blake3::Hash::from(read_array::<32>(&mut reader)?);
+                        let name_len = read_u32(&mut reader)?;
+                        let mut name = Vec::new();
+                        (&mut reader).take(name_len as u64).read_to_end(&mut name)?;
+                        ensure!(name.len() == name_len as usize, "truncated bundle");
+                        let name = String::from_utf8(name).context("invalid child name")?;
+                        ensure!(
+                            !name.is_empty() && !name.contains('/') && !name.contains('\0'),
+                            "invalid child name {:?}",
+                            name,
+                        );
+                        tree.add_child(name, &child_id, node_type);
+                    }
+                    ensure!(
+                        tree.len() == child_count as usize,
+                        "tree {} has duplicate children",
+                        tree_id,
+                    );
+                    ensure!(
+                        tree.id() == tree_id,
+                        "tree {} doesn't match its contents",
+                        tree_id,
+                    );
+                    trees.push(tree);
+                }
+                _ => bail!("unknown bundle record type {}", tag),
+            }
+        }
+
+        // Deferred transactions are vulnerable to BUSY errors if there are concurrent writers.
+        // See: https://fractaledmind.github.io/2024/04/15/sqlite-on-rails-the-how-and-why-of-optimal-performance/
+        let tx = self.conn.transaction_with_behavior(Immediate)?;
+        let persisted_paths =
+            insert_imported_blobs(&tx, &self.blobs_dir, self.compression, self.chunking, blobs)?;
+        // This checks that every child exists, either from earlier in the bundle or from before.
+        for tree in &trees {
+            insert_tree_rows(&tx, tree)?;
+        }
+        for root_id in &root_ids {
+            ensure!(
+                tree_exists(&tx, root_id)?,
+                "root tree {} is missing",
+                root_id
+            );
+        }
+
+        // Commit!
+        tx.commit()?;
+
+        // Finally, make the new files read-only.
+        for path in persisted_paths {
+            let mut permissions = fs::metadata(&path)?.permissions();
+            permissions.set_readonly(true);
+            fs::set_permissions(&path, permissions)?;
+        }
+        Ok(root_ids)
+    }
+}
+
+// Hashes everything written through it, building its outboard too.
+struct HashingWriter<'a, W>(&'a mut OutboardEncoder, W);
+
+impl<W: Write> Write for HashingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.1.write(buf)?;
+        self.0.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.1.flush()
+    }
+}
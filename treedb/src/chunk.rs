@@ -0,0 +1,200 @@
+use crate::compress::{self, Codec};
+use anyhow::{Context, ensure};
+use std::io::{self, SeekFrom, prelude::*};
+
+// Chunk sizes for content-defined chunking. Boundaries are only considered after MIN_CHUNK
+// bytes, and on average a boundary occurs 1 / 2^CHUNK_BITS of the time after that. Every
+// chunk is small enough to store in the chunks table.
+const MIN_CHUNK: usize = 1 << 13; // 8 KiB
+const MAX_CHUNK: usize = 1 << 16; // 64 KiB
+const CHUNK_BITS: u32 = 14;
+
+// Random values for the gear hash, generated with SplitMix64 so that chunk boundaries never
+// change.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+// Returns the length of the first chunk in `data`, which must either be at least MAX_CHUNK bytes
+// long or contain the rest of the blob. The gear hash only depends on the last 64 bytes, so
+// boundaries resynchronize shortly after an edit.
+fn chunk_len(data: &[u8]) -> usize {
+    let end = data.len().min(MAX_CHUNK);
+    if end <= MIN_CHUNK {
+        return end;
+    }
+    let mut hash: u64 = 0;
+    for (i, &byte) in data[..end].iter().enumerate().skip(MIN_CHUNK) {
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        // Test the high bits. Each shift pushes older bytes further up, so they depend on the
+        // whole 64-byte window, while the low bits only depend on the last few bytes.
+        if hash >> (64 - CHUNK_BITS) == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// Splits `reader` into content-defined chunks and inserts them, along with the chunk list for
+/// `blob_id`. Chunks that already exist are shared. Each chunk is compressed on its own if a
+/// `level` is given. The caller is responsible for the transaction, and for inserting the blob
+/// row with [`Codec::Chunked`].
+pub(crate) fn insert_chunks(
+    conn: &rusqlite::Connection,
+    blob_id: &blake3::Hash,
+    mut reader: impl Read,
+    level: Option<i32>,
+) -> anyhow::Result<()> {
+    let mut buffer = Vec::with_capacity(2 * MAX_CHUNK);
+    let mut offset: u64 = 0;
+    let mut eof = false;
+    loop {
+        if !eof && buffer.len() < MAX_CHUNK {
+            let wanted = 2 * MAX_CHUNK - buffer.len();
+            let n = Read::by_ref(&mut reader)
+                .take(wanted as u64)
+                .read_to_end(&mut buffer)?;
+            eof = n < wanted;
+        }
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        let len = chunk_len(&buffer);
+        let chunk = &buffer[..len];
+        let chunk_id = blake3::hash(chunk);
+        let (data, codec) = compress::encode_bytes(chunk, level)?;
+        conn.execute(
+            "INSERT OR IGNORE INTO chunks (chunk_id, data, codec) VALUES (?, ?, ?)",
+            (chunk_id.as_bytes(), &data, codec.to_column()),
+        )?;
+        conn.execute(
+            "INSERT INTO blob_chunks (blob_id, offset, len, chunk_id) VALUES (?, ?, ?, ?)",
+            (blob_id.as_bytes(), offset, len as u64, chunk_id.as_bytes()),
+        )?;
+        offset += len as u64;
+        buffer.drain(..len);
+    }
+}
+
+/// A `Read + Seek` view of a chunked blob. Chunks are loaded one at a time as they're read.
+pub(crate) struct ChunkedReader<'a> {
+    conn: &'a rusqlite::Connection,
+    // `(offset, chunk_id)`, sorted by offset.
+    chunks: Vec<(u64, blake3::Hash)>,
+    len: u64,
+    position: u64,
+    // The index and decompressed contents of the chunk containing `position`, if it's loaded.
+    current: Option<(usize, Vec<u8>)>,
+}
+
+impl<'a> ChunkedReader<'a> {
+    pub(crate) fn new(
+        conn: &'a rusqlite::Connection,
+        blob_id: &blake3::Hash,
+    ) -> anyhow::Result<Self> {
+        let mut query = conn.prepare(
+            "SELECT offset, len, chunk_id FROM blob_chunks WHERE blob_id = ? ORDER BY offset",
+        )?;
+        let rows = query.query_map((blob_id.as_bytes(),), |row| {
+            let chunk_id: [u8; 32] = row.get(2)?;
+            Ok((row.get(0)?, row.get(1)?, chunk_id.into()))
+        })?;
+        let mut chunks = Vec::new();
+        let mut len = 0;
+        for row in rows {
+            let (offset, chunk_len, chunk_id): (u64, u64, blake3::Hash) = row?;
+            ensure!(
+                offset == len,
+                "blob {} has a gap in its chunk list",
+                blob_id
+            );
+            len += chunk_len;
+            chunks.push((offset, chunk_id));
+        }
+        Ok(Self {
+            conn,
+            chunks,
+            len,
+            position: 0,
+            current: None,
+        })
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+fn load_chunk(conn: &rusqlite::Connection, chunk_id: &blake3::Hash) -> anyhow::Result<Vec<u8>> {
+    let (data, codec): (Vec<u8>, u8) = conn
+        .query_row(
+            "SELECT data, codec FROM chunks WHERE chunk_id = ?",
+            (chunk_id.as_bytes(),),
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .with_context(|| format!("failed to load chunk {}", chunk_id))?;
+    compress::decode_bytes(data, Codec::from_column(codec)?)
+}
+
+impl Read for ChunkedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        // The last chunk that starts at or before the current position.
+        let index = self
+            .chunks
+            .partition_point(|&(offset, _)| offset <= self.position)
+            - 1;
+        if self.current.as_ref().is_none_or(|(i, _)| *i != index) {
+            let data = load_chunk(self.conn, &self.chunks[index].1).map_err(io::Error::other)?;
+            let expected_len = self
+                .chunks
+                .get(index + 1)
+                .map_or(self.len, |&(next, _)| next)
+                - self.chunks[index].0;
+            if data.len() as u64 != expected_len {
+                return Err(io::Error::other(format!(
+                    "chunk {} doesn't match the chunk list",
+                    self.chunks[index].1,
+                )));
+            }
+            self.current = Some((index, data));
+        }
+        let (_, data) = self.current.as_ref().unwrap();
+        let start = (self.position - self.chunks[index].0) as usize;
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for ChunkedReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        let Some(target) = target else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            ));
+        };
+        self.position = target;
+        Ok(target)
+    }
+}
@@ -0,0 +1,200 @@
+use anyhow::{Context, bail};
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io::{self, BufReader, SeekFrom, prelude::*};
+use std::path::Path;
+
+/// How a blob's data is stored, in the `codec` column of the blobs table. Blob IDs are always
+/// the hash of the uncompressed data, so blobs with different codecs can live side by side.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Codec {
+    None = 0,
+    Zstd = 1,
+    /// Large blobs only. The data is split into rows of the chunks table, listed in order in the
+    /// blob_chunks table, and there's no file in the blobs dir. See the chunk module.
+    Chunked = 2,
+}
+
+impl Codec {
+    pub(crate) fn from_column(codec: u8) -> anyhow::Result<Self> {
+        Ok(match codec {
+            0 => Codec::None,
+            1 => Codec::Zstd,
+            2 => Codec::Chunked,
+            _ => bail!("unknown blob codec: {}", codec),
+        })
+    }
+
+    pub(crate) fn to_column(self) -> u8 {
+        self as u8
+    }
+}
+
+/// Compresses `data` if a `level` is given and compressing actually makes it smaller. Returns
+/// the bytes to store and their codec.
+pub(crate) fn encode_bytes(
+    data: &[u8],
+    level: Option<i32>,
+) -> anyhow::Result<(Cow<'_, [u8]>, Codec)> {
+    if let Some(level) = level {
+        // Single-shot compression records the content size in the frame header, which
+        // decoded_len relies on.
+        let compressed = zstd::bulk::compress(data, level)?;
+        if compressed.len() < data.len() {
+            return Ok((Cow::Owned(compressed), Codec::Zstd));
+        }
+    }
+    Ok((Cow::Borrowed(data), Codec::None))
+}
+
+/// The inverse of [`encode_bytes`].
+pub(crate) fn decode_bytes(data: Vec<u8>, codec: Codec) -> anyhow::Result<Vec<u8>> {
+    match codec {
+        Codec::None => Ok(data),
+        Codec::Zstd => Ok(zstd::decode_all(&data[..]).context("failed to decompress blob")?),
+        Codec::Chunked => bail!("chunked blobs aren't stored as bytes"),
+    }
+}
+
+/// Copies the file at `source` to `destination` in the blobs dir, replacing any leftover file
+/// from an insert that failed before committing. If a `level` is given and compressing makes the
+/// file smaller, the copy is compressed. Otherwise it's reflinked if possible.
+pub(crate) fn store_file(
+    source: &Path,
+    destination: &Path,
+    level: Option<i32>,
+) -> anyhow::Result<Codec> {
+    if fs::exists(destination)? {
+        // reflink_or_copy() requires the destination to be clear.
+        fs::remove_file(destination)?;
+    }
+    if let Some(level) = level {
+        let source_file = File::open(source)
+            .with_context(|| format!("failed to open {}", source.to_string_lossy()))?;
+        let source_len = source_file.metadata()?.len();
+        let destination_file = File::create(destination)
+            .with_context(|| format!("failed to create {}", destination.to_string_lossy()))?;
+        let mut encoder = zstd::Encoder::new(destination_file, level)?;
+        // Record the content size in the frame header. See decoded_len.
+        encoder.set_pledged_src_size(Some(source_len))?;
+        encoder.include_contentsize(true)?;
+        io::copy(&mut source_file.take(source_len), &mut encoder).with_context(|| {
+            format!(
+                "failed to compress {} to {}",
+                source.to_string_lossy(),
+                destination.to_string_lossy(),
+            )
+        })?;
+        let compressed_len = encoder.finish()?.metadata()?.len();
+        if compressed_len < source_len {
+            return Ok(Codec::Zstd);
+        }
+        fs::remove_file(destination)?;
+    }
+    reflink_copy::reflink_or_copy(source, destination).with_context(|| {
+        format!(
+            "failed to copy {} to {}",
+            source.to_string_lossy(),
+            destination.to_string_lossy(),
+        )
+    })?;
+    Ok(Codec::None)
+}
+
+/// Returns the uncompressed length of a large blob file.
+pub(crate) fn decoded_len(file: &mut File, codec: Codec) -> anyhow::Result<u64> {
+    match codec {
+        Codec::None => Ok(file.metadata()?.len()),
+        Codec::Zstd => {
+            // Every compressed blob has its content size in the frame header, which is at most
+            // 18 bytes.
+            let mut header = Vec::new();
+            Read::by_ref(file).take(18).read_to_end(&mut header)?;
+            file.rewind()?;
+            match zstd::zstd_safe::get_frame_content_size(&header) {
+                Ok(Some(len)) => Ok(len),
+                _ => bail!("compressed blob has no content size"),
+            }
+        }
+        Codec::Chunked => bail!("chunked blobs aren't stored as files"),
+    }
+}
+
+/// Returns a reader for the uncompressed contents of a large blob file.
+pub(crate) fn decoder(file: File, codec: Codec) -> anyhow::Result<Box<dyn Read>> {
+    Ok(match codec {
+        Codec::None => Box::new(file),
+        Codec::Zstd => Box::new(zstd::Decoder::new(file)?),
+        Codec::Chunked => bail!("chunked blobs aren't stored as files"),
+    })
+}
+
+/// A `Read + Seek` view of a compressed large blob file. Zstd frames can't be decoded from the
+/// middle, so seeking backwards restarts from the beginning, and seeking forwards decodes and
+/// discards everything in between.
+pub(crate) struct SeekableDecoder {
+    decoder: zstd::Decoder<'static, BufReader<File>>,
+    position: u64,
+    len: u64,
+}
+
+impl SeekableDecoder {
+    pub(crate) fn new(file: File, len: u64) -> io::Result<Self> {
+        Ok(Self {
+            decoder: zstd::Decoder::new(file)?,
+            position: 0,
+            len,
+        })
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    // Starts decoding again from the beginning. The new decoder reads through its own handle to
+    // the file, so if anything fails, the current one is left as it was.
+    fn restart(&mut self) -> io::Result<()> {
+        let file = self.decoder.get_ref().get_ref().try_clone()?;
+        let mut decoder = zstd::Decoder::new(file)?;
+        decoder.get_mut().get_mut().rewind()?;
+        self.decoder = decoder;
+        self.position = 0;
+        Ok(())
+    }
+}
+
+impl Read for SeekableDecoder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.decoder.read(buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for SeekableDecoder {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        let Some(target) = target else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            ));
+        };
+        if target < self.position {
+            self.restart()?;
+        }
+        // Like a file, seeking past the end is allowed, and reads there return nothing. If the
+        // skip fails partway, the position still counts what was skipped.
+        let skip = target.min(self.len).saturating_sub(self.position);
+        let mut skipped = Read::by_ref(&mut self.decoder).take(skip);
+        let result = io::copy(&mut skipped, &mut io::sink());
+        self.position += skip - skipped.limit();
+        result?;
+        self.position = target;
+        Ok(target)
+    }
+}
@@ -0,0 +1,150 @@
+use crate::{NodeType, TreeDb};
+use anyhow::bail;
+
+/// One difference between two trees, as returned by [`TreeDb::diff_trees`]. Paths are relative to
+/// the root trees and separated by `/`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DiffEntry {
+    /// An entry exists only in the new tree. For a subtree, only the subtree itself is reported,
+    /// not its contents.
+    Added {
+        path: String,
+        id: blake3::Hash,
+        node_type: NodeType,
+    },
+    /// An entry exists only in the old tree. For a subtree, only the subtree itself is reported,
+    /// not its contents.
+    Removed {
+        path: String,
+        id: blake3::Hash,
+        node_type: NodeType,
+    },
+    /// A blob changed contents, executable bit, or both, or a symlink changed its target. The old
+    /// and new node types are either both blobs or both symlinks.
+    Modified {
+        path: String,
+        old_id: blake3::Hash,
+        old_node_type: NodeType,
+        new_id: blake3::Hash,
+        new_node_type: NodeType,
+    },
+    /// An entry changed between a blob, a tree, and a symlink.
+    TypeChanged {
+        path: String,
+        old_id: blake3::Hash,
+        old_node_type: NodeType,
+        new_id: blake3::Hash,
+        new_node_type: NodeType,
+    },
+}
+
+impl DiffEntry {
+    pub fn path(&self) -> &str {
+        match self {
+            DiffEntry::Added { path, .. }
+            | DiffEntry::Removed { path, .. }
+            | DiffEntry::Modified { path, .. }
+            | DiffEntry::TypeChanged { path, .. } => path,
+        }
+    }
+}
+
+// Blobs with different executable bits count as the same kind.
+fn is_same_kind(a: NodeType, b: NodeType) -> bool {
+    matches!(
+        (a, b),
+        (NodeType::Blob { .. }, NodeType::Blob { .. })
+            | (NodeType::Tree, NodeType::Tree)
+            | (NodeType::Symlink, NodeType::Symlink)
+    )
+}
+
+pub(crate) fn join_path(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{prefix}/{name}")
+    }
+}
+
+impl TreeDb {
+    /// Returns the differences between two stored trees, sorted by path. Subtrees with the same
+    /// ID on both sides are skipped without being read.
+    pub fn diff_trees(
+        &mut self,
+        old_tree_id: &blake3::Hash,
+        new_tree_id: &blake3::Hash,
+    ) -> anyhow::Result<Vec<DiffEntry>> {
+        let mut entries = Vec::new();
+        self.diff_trees_inner(old_tree_id, new_tree_id, "", &mut entries)?;
+        Ok(entries)
+    }
+
+    fn diff_trees_inner(
+        &mut self,
+        old_tree_id: &blake3::Hash,
+        new_tree_id: &blake3::Hash,
+        prefix: &str,
+        entries: &mut Vec<DiffEntry>,
+    ) -> anyhow::Result<()> {
+        if old_tree_id == new_tree_id {
+            return Ok(());
+        }
+        let Some(old_tree) = self.get_tree(old_tree_id)? else {
+            bail!("tree {} doesn't exist", old_tree_id);
+        };
+        let Some(new_tree) = self.get_tree(new_tree_id)? else {
+            bail!("tree {} doesn't exist", new_tree_id);
+        };
+        let mut names: Vec<&String> = old_tree
+            .children
+            .keys()
+            .chain(new_tree.children.keys())
+            .collect();
+        names.sort();
+        names.dedup();
+        for name in names {
+            let path = join_path(prefix, name);
+            let old = old_tree.children.get(name).copied();
+            let new = new_tree.children.get(name).copied();
+            match (old, new) {
+                (Some(old), Some(new)) if old == new => {}
+                (Some((old_id, NodeType::Tree)), Some((new_id, NodeType::Tree))) => {
+                    self.diff_trees_inner(&old_id, &new_id, &path, entries)?;
+                }
+                (Some((old_id, old_node_type)), Some((new_id, new_node_type)))
+                    if is_same_kind(old_node_type, new_node_type) =>
+                {
+                    entries.push(DiffEntry::Modified {
+                        path,
+                        old_id,
+                        old_node_type,
+                        new_id,
+                        new_node_type,
+                    })
+                }
+                (Some((old_id, old_node_type)), Some((new_id, new_node_type))) => {
+                    entries.push(DiffEntry::TypeChanged {
+                        path,
+                        old_id,
+                        old_node_type,
+                        new_id,
+                        new_node_type,
+                    })
+                }
+                (Some((id, node_type)), None) => entries.push(DiffEntry::Removed {
+                    path,
+                    id,
+                    node_type,
+                }),
+                (None, Some((id, node_type))) => entries.push(DiffEntry::Added {
+                    path,
+                    id,
+                    node_type,
+                }),
+                (None, None) => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,530 @@
+use crate::chunk;
+use crate::compress::{self, Codec};
+use crate::gc;
+use crate::outboard;
+use crate::{LARGE_BLOB_THRESHOLD, NodeType, Tree, TreeDb, insert_tree_rows};
+use anyhow::{Context, bail, ensure};
+use rayon::prelude::*;
+use rusqlite::TransactionBehavior::Immediate;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+// The result of walking a directory, before any file contents have been read. Files are
+// represented as indexes into a flat list, so that they can all be hashed in parallel.
+struct ScannedDir {
+    entries: Vec<(String, ScannedEntry)>,
+}
+
+enum ScannedEntry {
+    File { index: usize, executable: bool },
+    Dir(ScannedDir),
+    Symlink { target: Vec<u8> },
+}
+
+struct HashedFile {
+    path: PathBuf,
+    id: blake3::Hash,
+    metadata: fs::Metadata,
+    // None for large files, which get copied into the blobs dir rather than read into memory.
+    small_data: Option<Vec<u8>>,
+    // Computed while hashing large files, for read_range(). Empty for small ones.
+    outboard: Vec<u8>,
+}
+
+#[cfg(unix)]
+pub(crate) fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_executable(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+// Blobs in the blobs dir are read-only, and reflinking/copying them preserves that, so set the
+// permissions on checked-out files explicitly.
+#[cfg(unix)]
+fn set_file_mode(path: &Path, executable: bool) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = if executable { 0o755 } else { 0o644 };
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .with_context(|| format!("failed to set permissions on {}", path.to_string_lossy()))
+}
+
+#[cfg(not(unix))]
+fn set_file_mode(path: &Path, _executable: bool) -> anyhow::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(false);
+    fs::set_permissions(path, permissions)
+        .with_context(|| format!("failed to set permissions on {}", path.to_string_lossy()))
+}
+
+#[cfg(unix)]
+pub(crate) fn read_symlink(path: &Path) -> anyhow::Result<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+    let target = fs::read_link(path)
+        .with_context(|| format!("failed to read symlink {}", path.to_string_lossy()))?;
+    Ok(target.as_os_str().as_bytes().to_vec())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn read_symlink(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let target = fs::read_link(path)
+        .with_context(|| format!("failed to read symlink {}", path.to_string_lossy()))?;
+    let Some(target) = target.to_str() else {
+        bail!("target of {} is not valid UTF-8", path.to_string_lossy());
+    };
+    Ok(target.as_bytes().to_vec())
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &[u8], path: &Path) -> anyhow::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    std::os::unix::fs::symlink(std::ffi::OsStr::from_bytes(target), path)
+        .with_context(|| format!("failed to create symlink {}", path.to_string_lossy()))
+}
+
+#[cfg(not(unix))]
+fn create_symlink(target: &[u8], path: &Path) -> anyhow::Result<()> {
+    let target = std::str::from_utf8(target)?;
+    #[cfg(windows)]
+    let result = std::os::windows::fs::symlink_file(target, path);
+    #[cfg(not(windows))]
+    let result = Err::<(), _>(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("symlinks to {} aren't supported on this platform", target),
+    ));
+    result.with_context(|| format!("failed to create symlink {}", path.to_string_lossy()))
+}
+
+fn scan_dir(path: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<ScannedDir> {
+    let mut entries = Vec::new();
+    let read_dir = fs::read_dir(path)
+        .with_context(|| format!("failed to read directory {}", path.to_string_lossy()))?;
+    for entry in read_dir {
+        let entry = entry?;
+        let entry_path = entry.path();
+        let Ok(name) = entry.file_name().into_string() else {
+            bail!("{} is not valid UTF-8", entry_path.to_string_lossy());
+        };
+        let metadata = fs::symlink_metadata(&entry_path)
+            .with_context(|| format!("failed to stat {}", entry_path.to_string_lossy()))?;
+        if metadata.is_symlink() {
+            let target = read_symlink(&entry_path)?;
+            entries.push((name, ScannedEntry::Symlink { target }));
+        } else if metadata.is_dir() {
+            entries.push((name, ScannedEntry::Dir(scan_dir(&entry_path, files)?)));
+        } else if metadata.is_file() {
+            let index = files.len();
+            files.push(entry_path);
+            let executable = is_executable(&metadata);
+            entries.push((name, ScannedEntry::File { index, executable }));
+        } else {
+            bail!(
+                "{} is not a file, directory, or symlink",
+                entry_path.to_string_lossy()
+            );
+        }
+    }
+    Ok(ScannedDir { entries })
+}
+
+fn hash_file(path: &Path) -> anyhow::Result<HashedFile> {
+    let file = File::open(path)
+        .with_context(|| format!("failed to open file at {}", path.to_string_lossy()))?;
+    let metadata = file.metadata()?;
+    if metadata.len() < LARGE_BLOB_THRESHOLD as u64 {
+        let mut data = Vec::with_capacity(metadata.len() as usize);
+        // As in insert_file, .take() guards against files that grow while we're reading them.
+        file.take(metadata.len())
+            .read_to_end(&mut data)
+            .with_context(|| format!("failed to read file at {}", path.to_string_lossy()))?;
+        return Ok(HashedFile {
+            path: path.to_owned(),
+            id: blake3::hash(&data),
+            metadata,
+            small_data: Some(data),
+            outboard: Vec::new(),
+        });
+    }
+    let (id, outboard) = outboard::encode_file(path, metadata.len())?;
+    Ok(HashedFile {
+        path: path.to_owned(),
+        id,
+        metadata,
+        small_data: None,
+        outboard,
+    })
+}
+
+// One step of an incremental checkout. These are all computed (and checked against the disk)
+// before any of them are applied, so that a conflict doesn't leave the directory half-updated.
+enum CheckoutOp {
+    Remove(PathBuf),
+    WriteBlob(PathBuf, blake3::Hash, bool),
+    WriteTree(PathBuf, blake3::Hash),
+    WriteSymlink(PathBuf, blake3::Hash),
+    SetExecutable(PathBuf, bool),
+}
+
+fn remove_path(path: &Path) -> anyhow::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if metadata.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+    .with_context(|| format!("failed to remove {}", path.to_string_lossy()))
+}
+
+// The caller is responsible for the transaction, and for making sure the blob is small.
+fn insert_small_blob(
+    conn: &rusqlite::Connection,
+    blob_id: &blake3::Hash,
+    data: &[u8],
+    compression: Option<i32>,
+) -> anyhow::Result<()> {
+    debug_assert!(data.len() < LARGE_BLOB_THRESHOLD);
+    let (data, codec) = compress::encode_bytes(data, compression)?;
+    conn.execute(
+        "INSERT OR IGNORE INTO blobs (blob_id, data, codec) VALUES (?, ?, ?)",
+        (blob_id.as_bytes(), &data, codec.to_column()),
+    )?;
+    Ok(())
+}
+
+// Inserts the trees for `dir` bottom-up, returning the ID of `dir` itself.
+fn insert_scanned_dir(
+    conn: &rusqlite::Connection,
+    dir: &ScannedDir,
+    hashed_files: &[HashedFile],
+    compression: Option<i32>,
+) -> anyhow::Result<blake3::Hash> {
+    let mut tree = Tree::new();
+    for (name, entry) in &dir.entries {
+        match entry {
+            ScannedEntry::File { index, executable } => {
+                let executable = *executable;
+                tree.add_child(
+                    name.clone(),
+                    &hashed_files[*index].id,
+                    NodeType::Blob { executable },
+                );
+            }
+            ScannedEntry::Dir(subdir) => {
+                let subdir_id = insert_scanned_dir(conn, subdir, hashed_files, compression)?;
+                tree.add_child(name.clone(), &subdir_id, NodeType::Tree);
+            }
+            ScannedEntry::Symlink { target } => {
+                ensure!(
+                    target.len() < LARGE_BLOB_THRESHOLD,
+                    "symlink target is too long",
+                );
+                let target_id = blake3::hash(target);
+                insert_small_blob(conn, &target_id, target, compression)?;
+                gc::mark_recent(conn, &target_id)?;
+                tree.add_child(name.clone(), &target_id, NodeType::Symlink);
+            }
+        }
+    }
+    insert_tree_rows(conn, &tree)
+}
+
+impl TreeDb {
+    /// Recursively snapshots the directory at `path` and returns the ID of its root tree. All the
+    /// blobs and trees are inserted in a single transaction. Symlinks are stored as
+    /// [`NodeType::Symlink`], not followed.
+    pub fn insert_dir(&mut self, path: impl AsRef<Path>) -> anyhow::Result<blake3::Hash> {
+        let mut file_paths = Vec::new();
+        let scanned = scan_dir(path.as_ref(), &mut file_paths)?;
+
+        // Read and hash all the files in parallel, before taking the write lock.
+        let hashed_files = file_paths
+            .par_iter()
+            .map(|path| hash_file(path))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let blob_paths: Vec<PathBuf> = hashed_files
+            .iter()
+            .map(|file| self.blob_path(&file.id))
+            .collect();
+
+        // Deferred transactions are vulnerable to BUSY errors if there are concurrent writers.
+        // See: https://fractaledmind.github.io/2024/04/15/sqlite-on-rails-the-how-and-why-of-optimal-performance/
+        let tx = self.conn.transaction_with_behavior(Immediate)?;
+        let mut copied_blob_paths = Vec::new();
+        for (file, blob_path) in hashed_files.iter().zip(&blob_paths) {
+            // Skip blobs that already exist, including duplicates within this directory.
+            gc::mark_recent(&tx, &file.id)?;
+            let exists: u64 = tx.query_row(
+                "SELECT COUNT(*) FROM blobs WHERE blob_id = ?",
+                (file.id.as_bytes(),),
+                |row| row.get(0),
+            )?;
+            assert!(exists <= 1);
+            if exists == 1 {
+                continue;
+            }
+
+            // Small blobs go in the blobs table.
+            if let Some(data) = &file.small_data {
+                insert_small_blob(&tx, &file.id, data, self.compression)?;
+                continue;
+            }
+
+            // Large blobs are chunked or go in the blobs dir. See insert_file for the details
+            // here.
+            let codec = if self.chunking {
+                let reader = File::open(&file.path)?.take(file.metadata.len());
+                chunk::insert_chunks(&tx, &file.id, reader, self.compression)?;
+                Codec::Chunked
+            } else {
+                compress::store_file(&file.path, blob_path, self.compression)?
+            };
+            outboard::insert_outboard(&tx, &file.id, &file.outboard)?;
+            tx.execute(
+                "INSERT INTO blobs (blob_id, data, codec) VALUES (?, NULL, ?)",
+                (file.id.as_bytes(), codec.to_column()),
+            )?;
+            let metadata_after = fs::metadata(&file.path)?;
+            ensure!(
+                file.metadata.modified()? == metadata_after.modified()?,
+                "{} was modified while it was being read",
+                file.path.to_string_lossy(),
+            );
+            #[cfg(not(windows))]
+            {
+                use std::os::unix::fs::MetadataExt;
+                ensure!(
+                    file.metadata.ino() == metadata_after.ino(),
+                    "{} was modified while it was being read",
+                    file.path.to_string_lossy(),
+                );
+            }
+            if codec != Codec::Chunked {
+                copied_blob_paths.push(blob_path);
+            }
+        }
+        let root_id = insert_scanned_dir(&tx, &scanned, &hashed_files, self.compression)?;
+
+        // Commit!
+        tx.commit()?;
+
+        // Finally, make the copied files read-only.
+        for blob_path in copied_blob_paths {
+            let mut permissions = fs::metadata(blob_path)?.permissions();
+            permissions.set_readonly(true);
+            fs::set_permissions(blob_path, permissions)?;
+        }
+        Ok(root_id)
+    }
+
+    /// Writes out the tree `tree_id` as a directory at `destination`, which must either not exist
+    /// or be an empty directory. Large blobs are reflinked from the blobs dir if possible, like
+    /// [`get_file`](Self::get_file).
+    pub fn checkout_tree(
+        &mut self,
+        tree_id: &blake3::Hash,
+        destination: impl AsRef<Path>,
+    ) -> anyhow::Result<()> {
+        let destination = destination.as_ref();
+        if fs::exists(destination)? {
+            let mut entries = fs::read_dir(destination).with_context(|| {
+                format!("failed to read directory {}", destination.to_string_lossy())
+            })?;
+            ensure!(
+                entries.next().is_none(),
+                "{} is not empty",
+                destination.to_string_lossy(),
+            );
+        }
+        self.checkout_tree_inner(tree_id, destination)
+    }
+
+    fn checkout_tree_inner(
+        &mut self,
+        tree_id: &blake3::Hash,
+        destination: &Path,
+    ) -> anyhow::Result<()> {
+        let Some(tree) = self.get_tree(tree_id)? else {
+            bail!("tree {} doesn't exist", tree_id);
+        };
+        fs::create_dir_all(destination)
+            .with_context(|| format!("creating directory {}", destination.to_string_lossy()))?;
+        for child in tree.iter() {
+            let child_path = destination.join(child.name);
+            match child.node_type {
+                NodeType::Blob { executable } => {
+                    self.get_file(child.id, &child_path)?;
+                    set_file_mode(&child_path, executable)?;
+                }
+                NodeType::Tree => self.checkout_tree_inner(child.id, &child_path)?,
+                NodeType::Symlink => create_symlink(&self.get_blob(child.id)?, &child_path)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Transforms `directory`, which currently contains a checkout of `old_tree_id`, into a
+    /// checkout of `new_tree_id`. Only the entries that differ are touched, and subtrees with the
+    /// same ID are skipped entirely. Unless `force` is true, this returns an error without
+    /// modifying anything if a file that would be removed or rewritten no longer matches
+    /// `old_tree_id`, or if an untracked file is in the way.
+    pub fn update_checkout(
+        &mut self,
+        old_tree_id: &blake3::Hash,
+        new_tree_id: &blake3::Hash,
+        directory: impl AsRef<Path>,
+        force: bool,
+    ) -> anyhow::Result<()> {
+        let mut ops = Vec::new();
+        self.plan_checkout(
+            old_tree_id,
+            new_tree_id,
+            directory.as_ref(),
+            force,
+            &mut ops,
+        )?;
+        for op in ops {
+            match op {
+                CheckoutOp::Remove(path) => remove_path(&path)?,
+                CheckoutOp::WriteBlob(path, blob_id, executable) => {
+                    // Only possible with `force`. Otherwise the path was checked to be clear.
+                    remove_path(&path)?;
+                    self.get_file(&blob_id, &path)?;
+                    set_file_mode(&path, executable)?;
+                }
+                CheckoutOp::WriteTree(path, tree_id) => {
+                    remove_path(&path)?;
+                    self.checkout_tree_inner(&tree_id, &path)?;
+                }
+                CheckoutOp::WriteSymlink(path, target_id) => {
+                    remove_path(&path)?;
+                    create_symlink(&self.get_blob(&target_id)?, &path)?;
+                }
+                CheckoutOp::SetExecutable(path, executable) => set_file_mode(&path, executable)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn plan_checkout(
+        &mut self,
+        old_tree_id: &blake3::Hash,
+        new_tree_id: &blake3::Hash,
+        directory: &Path,
+        force: bool,
+        ops: &mut Vec<CheckoutOp>,
+    ) -> anyhow::Result<()> {
+        if old_tree_id == new_tree_id {
+            return Ok(());
+        }
+        let Some(old_tree) = self.get_tree(old_tree_id)? else {
+            bail!("tree {} doesn't exist", old_tree_id);
+        };
+        let Some(new_tree) = self.get_tree(new_tree_id)? else {
+            bail!("tree {} doesn't exist", new_tree_id);
+        };
+        let mut names: Vec<&String> = old_tree
+            .children
+            .keys()
+            .chain(new_tree.children.keys())
+            .collect();
+        names.sort();
+        names.dedup();
+        for name in names {
+            let path = directory.join(name);
+            let old = old_tree.children.get(name).copied();
+            let new = new_tree.children.get(name).copied();
+            match (old, new) {
+                (Some(old), Some(new)) if old == new => {}
+                (Some((old_id, NodeType::Tree)), Some((new_id, NodeType::Tree))) => {
+                    self.plan_checkout(&old_id, &new_id, &path, force, ops)?;
+                }
+                (
+                    Some((old_id, NodeType::Blob { .. })),
+                    Some((new_id, NodeType::Blob { executable })),
+                ) if old_id == new_id => {
+                    if !force {
+                        self.check_unmodified(&path, &old_id, NodeType::Blob { executable })?;
+                    }
+                    ops.push(CheckoutOp::SetExecutable(path, executable));
+                }
+                (old, new) => {
+                    if let Some((old_id, old_type)) = old {
+                        if !force {
+                            self.check_unmodified(&path, &old_id, old_type)?;
+                        }
+                        ops.push(CheckoutOp::Remove(path.clone()));
+                    } else if !force {
+                        ensure!(
+                            fs::symlink_metadata(&path).is_err(),
+                            "untracked {} would be overwritten",
+                            path.to_string_lossy(),
+                        );
+                    }
+                    match new {
+                        Some((new_id, NodeType::Blob { executable })) => {
+                            ops.push(CheckoutOp::WriteBlob(path, new_id, executable));
+                        }
+                        Some((new_id, NodeType::Tree)) => {
+                            ops.push(CheckoutOp::WriteTree(path, new_id));
+                        }
+                        Some((new_id, NodeType::Symlink)) => {
+                            ops.push(CheckoutOp::WriteSymlink(path, new_id));
+                        }
+                        None => {}
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Returns an error if `path` doesn't match the given tree or blob. For trees, extra untracked
+    // files count as modifications, since removing the tree would delete them.
+    fn check_unmodified(
+        &mut self,
+        path: &Path,
+        id: &blake3::Hash,
+        node_type: NodeType,
+    ) -> anyhow::Result<()> {
+        let modified = || format!("{} has been modified", path.to_string_lossy());
+        let metadata = fs::symlink_metadata(path).with_context(modified)?;
+        match node_type {
+            NodeType::Blob { .. } => {
+                ensure!(metadata.is_file(), modified());
+                ensure!(hash_file(path)?.id == *id, modified());
+            }
+            NodeType::Symlink => {
+                ensure!(metadata.is_symlink(), modified());
+                ensure!(blake3::hash(&read_symlink(path)?) == *id, modified());
+            }
+            NodeType::Tree => {
+                ensure!(metadata.is_dir(), modified());
+                let Some(tree) = self.get_tree(id)? else {
+                    bail!("tree {} doesn't exist", id);
+                };
+                let mut on_disk = 0;
+                for entry in fs::read_dir(path)? {
+                    let name = entry?.file_name();
+                    let tracked = name
+                        .to_str()
+                        .is_some_and(|name| tree.children.contains_key(name));
+                    ensure!(tracked, modified());
+                    on_disk += 1;
+                }
+                ensure!(on_disk == tree.len(), modified());
+                for child in tree.iter() {
+                    self.check_unmodified(&path.join(child.name), child.id, child.node_type)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
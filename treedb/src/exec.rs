@@ -0,0 +1,136 @@
+use crate::dir::{is_executable, read_symlink};
+use crate::path::split_path;
+use crate::{Action, ActionResult, NodeType, Tree, TreeDb, TreeEdit};
+use anyhow::{Context, bail, ensure};
+use std::fs;
+use std::io;
+use std::process::{Command, ExitStatus, Output, Stdio};
+
+// Checks that an output path stays inside the sandbox, and returns it in canonical form.
+fn output_path(path: &str) -> anyhow::Result<String> {
+    ensure!(!path.starts_with('/'), "output path {:?} is absolute", path);
+    let components: Vec<&str> = split_path(path).collect();
+    ensure!(!components.is_empty(), "output path {:?} is empty", path);
+    for component in &components {
+        ensure!(
+            *component != "." && *component != "..",
+            "output path {:?} isn't normalized",
+            path,
+        );
+    }
+    Ok(components.join("/"))
+}
+
+// Processes killed by a signal get the same exit code a shell would report.
+#[cfg(unix)]
+fn exit_code(status: ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+    status
+        .code()
+        .unwrap_or_else(|| 128 + status.signal().unwrap_or(0))
+}
+
+#[cfg(not(unix))]
+fn exit_code(status: ExitStatus) -> i32 {
+    status.code().unwrap_or(-1)
+}
+
+// An action that's been checked out and is ready to run. Running the command doesn't touch the
+// database, so the scheduler can do that on another thread.
+pub(crate) struct Sandbox {
+    dir: tempfile::TempDir,
+    // Normalized, with parents before their children.
+    output_paths: Vec<String>,
+}
+
+impl Sandbox {
+    pub(crate) fn command(&self, action: &Action) -> Command {
+        let mut command = Command::new(&action.argv[0]);
+        command
+            .args(&action.argv[1..])
+            .env_clear()
+            .envs(&action.env)
+            .current_dir(self.dir.path())
+            .stdin(Stdio::null());
+        command
+    }
+}
+
+impl TreeDb {
+    /// Runs `action` locally and returns its result, without consulting or updating the action
+    /// cache.
+    ///
+    /// The input tree is checked out into a fresh temporary directory, and the command runs there
+    /// with only the action's environment and with stdin closed. Afterwards each of the output
+    /// paths that exists is inserted, and together they make up the output tree. Outputs that
+    /// don't exist are left out, so callers should check for them if the command succeeded. Exit
+    /// status and stdout and stderr are recorded even if the command fails. Returns an error if
+    /// the command can't be started at all.
+    pub fn run_action(&mut self, action: &Action) -> anyhow::Result<ActionResult> {
+        let sandbox = self.prepare_sandbox(action)?;
+        let output = sandbox
+            .command(action)
+            .output()
+            .with_context(|| format!("failed to run {:?}", action.argv[0]))?;
+        self.finish_sandbox(sandbox, output)
+    }
+
+    pub(crate) fn prepare_sandbox(&mut self, action: &Action) -> anyhow::Result<Sandbox> {
+        ensure!(!action.argv.is_empty(), "action has an empty command line");
+        let mut output_paths = action
+            .output_paths
+            .iter()
+            .map(|path| output_path(path))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        // Parent directories come first, so that they don't replace their children in the output
+        // tree.
+        output_paths.sort_by_key(|path| path.matches('/').count());
+        let dir = tempfile::tempdir()?;
+        self.checkout_tree(&action.input_tree, dir.path())?;
+        Ok(Sandbox { dir, output_paths })
+    }
+
+    pub(crate) fn finish_sandbox(
+        &mut self,
+        sandbox: Sandbox,
+        output: Output,
+    ) -> anyhow::Result<ActionResult> {
+        let empty_id = self.insert_tree(&Tree::new())?;
+        let mut edits = Vec::new();
+        for path in sandbox.output_paths {
+            let disk_path = sandbox.dir.path().join(&path);
+            let metadata = match fs::symlink_metadata(&disk_path) {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("failed to read output {}", path));
+                }
+            };
+            let (id, node_type) = if metadata.is_symlink() {
+                (
+                    self.insert_blob(&read_symlink(&disk_path)?)?,
+                    NodeType::Symlink,
+                )
+            } else if metadata.is_dir() {
+                (self.insert_dir(&disk_path)?, NodeType::Tree)
+            } else if metadata.is_file() {
+                let executable = is_executable(&metadata);
+                (self.insert_file(&disk_path)?, NodeType::Blob { executable })
+            } else {
+                bail!("output {} is not a file, directory, or symlink", path);
+            };
+            edits.push(TreeEdit::Set {
+                path,
+                id,
+                node_type,
+            });
+        }
+
+        Ok(ActionResult {
+            output_tree: self.edit_tree(&empty_id, &edits)?,
+            exit_code: exit_code(output.status),
+            stdout: self.insert_blob(&output.stdout)?,
+            stderr: self.insert_blob(&output.stderr)?,
+        })
+    }
+}
@@ -0,0 +1,194 @@
+use crate::{TreeDb, tree_exists};
+use anyhow::{Context, ensure};
+use rusqlite::TransactionBehavior::Immediate;
+use std::fs;
+use std::path::PathBuf;
+
+/// What [`TreeDb::gc`] deleted.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct GcStats {
+    pub trees: u64,
+    pub blobs: u64,
+    /// Chunks that no remaining chunked blob uses.
+    pub chunks: u64,
+    /// Files removed from the blobs dir, including leftovers from failed inserts.
+    pub files: u64,
+}
+
+// Files that are about to be deleted get renamed with this suffix first. See below.
+const GARBAGE_SUFFIX: &str = ".garbage";
+
+pub(crate) fn is_blob_file_name(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Records that a blob or tree was just inserted, or that an insert found it already there, so
+/// that gc() keeps it for the grace period. The caller is responsible for the transaction.
+pub(crate) fn mark_recent(conn: &rusqlite::Connection, id: &blake3::Hash) -> anyhow::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO recent (id, inserted_at)
+         VALUES (?, CAST(strftime('%s', 'now') AS INTEGER))",
+        (id.as_bytes(),),
+    )?;
+    Ok(())
+}
+
+impl TreeDb {
+    /// Deletes every tree and blob that isn't reachable from `roots`, from any ref, or from any
+    /// cached action result, along with any files in the blobs dir that don't belong to a
+    /// remaining blob.
+    ///
+    /// The mark and sweep happen in a single IMMEDIATE transaction, which excludes all other
+    /// writers. A concurrent `insert_tree` either commits before the mark, in which case its
+    /// children are seen, or starts after the sweep, in which case its check that every child
+    /// exists will fail cleanly. Blobs and trees that were inserted within the grace period (see
+    /// [`set_gc_grace_period`](Self::set_gc_grace_period)) count as roots, so that writers have
+    /// time to link them into a tree.
+    pub fn gc(&mut self, roots: &[blake3::Hash]) -> anyhow::Result<GcStats> {
+        let mut stats = GcStats::default();
+        let tx = self.conn.transaction_with_behavior(Immediate)?;
+
+        // Mark.
+        tx.execute(
+            "CREATE TEMP TABLE IF NOT EXISTS gc_roots (tree_id BLOB NOT NULL PRIMARY KEY)",
+            (),
+        )?;
+        tx.execute("DELETE FROM gc_roots", ())?;
+        tx.execute(
+            "INSERT OR IGNORE INTO gc_roots (tree_id) SELECT tree_id FROM refs",
+            (),
+        )?;
+        tx.execute(
+            "INSERT OR IGNORE INTO gc_roots (tree_id) SELECT output_tree FROM actions",
+            (),
+        )?;
+        // Marks that have outlived the grace period aren't needed any more. Blobs go in gc_roots
+        // along with the trees, which works because they have no rows in the trees table.
+        tx.execute(
+            "DELETE FROM recent
+             WHERE inserted_at <= CAST(strftime('%s', 'now') AS INTEGER) - ?",
+            (self.gc_grace_period.as_secs() as i64,),
+        )?;
+        tx.execute(
+            "INSERT OR IGNORE INTO gc_roots (tree_id) SELECT id FROM recent",
+            (),
+        )?;
+        for root in roots {
+            ensure!(tree_exists(&tx, root)?, "root tree {} does not exist", root);
+            tx.execute(
+                "INSERT OR IGNORE INTO gc_roots (tree_id) VALUES (?)",
+                (root.as_bytes(),),
+            )?;
+        }
+        tx.execute(
+            "CREATE TEMP TABLE IF NOT EXISTS gc_reachable (id BLOB NOT NULL PRIMARY KEY)",
+            (),
+        )?;
+        tx.execute("DELETE FROM gc_reachable", ())?;
+        tx.execute(
+            "INSERT INTO gc_reachable (id)
+             WITH RECURSIVE reachable(id) AS (
+                 SELECT tree_id FROM gc_roots
+                 UNION
+                 SELECT trees.child_id FROM trees JOIN reachable ON trees.tree_id = reachable.id
+             )
+             SELECT id FROM reachable",
+            (),
+        )?;
+        // The stdout and stderr of cached action results are reachable too.
+        tx.execute(
+            "INSERT OR IGNORE INTO gc_reachable (id)
+             SELECT stdout FROM actions UNION SELECT stderr FROM actions",
+            (),
+        )?;
+
+        // Sweep the tables.
+        stats.trees = tx.query_row(
+            "SELECT COUNT(DISTINCT tree_id) FROM trees
+             WHERE tree_id NOT IN (SELECT id FROM gc_reachable)",
+            (),
+            |row| row.get(0),
+        )?;
+        tx.execute(
+            "DELETE FROM trees WHERE tree_id NOT IN (SELECT id FROM gc_reachable)",
+            (),
+        )?;
+        stats.blobs = tx.execute(
+            "DELETE FROM blobs WHERE blob_id NOT IN (SELECT id FROM gc_reachable)",
+            (),
+        )? as u64;
+        tx.execute(
+            "DELETE FROM blob_chunks WHERE blob_id NOT IN (SELECT blob_id FROM blobs)",
+            (),
+        )?;
+        tx.execute(
+            "DELETE FROM outboards WHERE blob_id NOT IN (SELECT blob_id FROM blobs)",
+            (),
+        )?;
+        stats.chunks = tx.execute(
+            "DELETE FROM chunks WHERE chunk_id NOT IN (SELECT chunk_id FROM blob_chunks)",
+            (),
+        )? as u64;
+
+        // Sweep the blobs dir. Every large blob that's still in the table keeps its file, and
+        // everything else named like a blob is either garbage or a leftover from an insert that
+        // failed before committing. (Writers only create these files while they hold the write
+        // lock, so there can't be one in progress right now.) We can't delete files until the
+        // transaction commits, because if it fails the rows for them would still be there. But
+        // we also can't wait to touch them until after we release the lock, because a concurrent
+        // writer might reinsert one of these blobs. So we rename them out of the way now, and
+        // delete them after committing.
+        let mut kept = std::collections::HashSet::new();
+        {
+            let mut query = tx.prepare("SELECT blob_id FROM blobs WHERE data IS NULL")?;
+            let rows = query.query_map((), |row| row.get::<_, [u8; 32]>(0))?;
+            for row in rows {
+                kept.insert(blake3::Hash::from(row?).to_hex().to_string());
+            }
+        }
+        let mut renamed: Vec<(PathBuf, PathBuf)> = Vec::new();
+        let mut stale_garbage: Vec<PathBuf> = Vec::new();
+        let rename_result = (|| -> anyhow::Result<()> {
+            for entry in fs::read_dir(&self.blobs_dir)? {
+                let entry = entry?;
+                let Ok(name) = entry.file_name().into_string() else {
+                    continue;
+                };
+                if name.ends_with(GARBAGE_SUFFIX) {
+                    // Left over from a GC that crashed after committing.
+                    stale_garbage.push(entry.path());
+                } else if is_blob_file_name(&name) && !kept.contains(&name) {
+                    let garbage_path = self.blobs_dir.join(format!("{name}{GARBAGE_SUFFIX}"));
+                    fs::rename(entry.path(), &garbage_path).with_context(|| {
+                        format!("failed to rename {}", entry.path().to_string_lossy())
+                    })?;
+                    renamed.push((entry.path(), garbage_path));
+                }
+            }
+            Ok(())
+        })();
+        let result = rename_result.and_then(|()| Ok(tx.commit()?));
+        if let Err(e) = result {
+            // Put everything back. The transaction rolls back when it's dropped.
+            for (original_path, garbage_path) in &renamed {
+                _ = fs::rename(garbage_path, original_path);
+            }
+            return Err(e);
+        }
+
+        // Committed. Now it's safe to actually delete things.
+        for path in renamed.iter().map(|(_, p)| p).chain(&stale_garbage) {
+            // Blob files are read-only, which prevents removing them on Windows.
+            #[cfg(windows)]
+            {
+                let mut permissions = fs::metadata(path)?.permissions();
+                permissions.set_readonly(false);
+                fs::set_permissions(path, permissions)?;
+            }
+            fs::remove_file(path)
+                .with_context(|| format!("failed to remove {}", path.to_string_lossy()))?;
+        }
+        stats.files = renamed.len() as u64;
+        Ok(stats)
+    }
+}
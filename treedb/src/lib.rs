@@ -1,17 +1,55 @@
 use anyhow::{Context, bail, ensure};
+use compress::Codec;
 use rusqlite::{OptionalExtension, TransactionBehavior::Immediate};
 use std::collections::BTreeMap;
 use std::fs::{self, File};
-use std::io::prelude::*;
+use std::io::{self, prelude::*};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+mod action;
+mod archive;
+mod blob_io;
+mod buildfile;
+mod bundle;
+mod chunk;
+mod compress;
+mod diff;
+mod dir;
+mod exec;
+mod gc;
+mod manifest;
+mod outboard;
+mod path;
+mod refs;
+mod schedule;
+mod stats;
+mod transfer;
+mod verify;
+mod walk;
+
+pub use action::{Action, ActionResult};
+pub use blob_io::{BlobReader, BlobWriter};
+pub use diff::DiffEntry;
+pub use gc::GcStats;
+pub use path::TreeEdit;
+pub use schedule::{BuildOptions, Target, TargetInput, TargetOutcome};
+pub use stats::StoreStats;
+pub use transfer::CopyStats;
+pub use verify::VerifyReport;
+pub use walk::{Walk, WalkEntry};
 
 #[cfg(test)]
 mod test;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum NodeType {
-    Blob { executable: bool },
+    Blob {
+        executable: bool,
+    },
     Tree,
+    /// The child ID of a symlink is the ID of a blob containing its target path.
+    Symlink,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -21,7 +59,21 @@ pub struct Child<'a> {
     node_type: NodeType,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+impl<'a> Child<'a> {
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    pub fn id(&self) -> &'a blake3::Hash {
+        self.id
+    }
+
+    pub fn node_type(&self) -> NodeType {
+        self.node_type
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct Tree {
     children: BTreeMap<String, (blake3::Hash, NodeType)>,
 }
@@ -37,7 +89,11 @@ impl Tree {
         self.children.len()
     }
 
-    pub fn get_child(&mut self, name: &str) -> Option<Child<'_>> {
+    pub fn is_empty(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    pub fn get_child(&self, name: &str) -> Option<Child<'_>> {
         debug_assert!(!name.is_empty());
         debug_assert!(!name.contains("/"));
         debug_assert!(!name.contains("\0"));
@@ -80,6 +136,7 @@ impl Tree {
                 NodeType::Blob { executable: false } => [0, 0],
                 NodeType::Blob { executable: true } => [0, 1],
                 NodeType::Tree => [1, 0],
+                NodeType::Symlink => [2, 0],
             };
             hasher.update(child.id.as_bytes());
             hasher.update(&node_type_bytes);
@@ -93,10 +150,18 @@ impl Tree {
 // Semi-arbitrary cutoff based on https://www.sqlite.org/intern-v-extern-blob.html.
 const LARGE_BLOB_THRESHOLD: usize = 1 << 16; // 64 KiB
 
+const DEFAULT_GC_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
 #[derive(Debug)]
 pub struct TreeDb {
     conn: rusqlite::Connection,
     blobs_dir: PathBuf,
+    // The zstd level for new blobs, or None to store them uncompressed.
+    compression: Option<i32>,
+    // Whether new large blobs are split into chunks, rather than stored as files.
+    chunking: bool,
+    // How long gc() keeps objects that were inserted recently, even if nothing links to them.
+    gc_grace_period: Duration,
 }
 
 impl TreeDb {
@@ -123,6 +188,44 @@ impl TreeDb {
             "CREATE TABLE IF NOT EXISTS blobs (
                  blob_id BLOB NOT NULL,
                  data BLOB,  -- NULL means data is in the large blobs dir
+                 codec TINYINT NOT NULL DEFAULT 0,  -- see compress::Codec
+                 PRIMARY KEY (blob_id))",
+            (),
+        )?;
+        // Databases created before compression was supported don't have the codec column. All
+        // of their blobs are uncompressed, which is what the default means.
+        let has_codec: bool = conn.query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('blobs') WHERE name = 'codec'",
+            (),
+            |row| row.get(0),
+        )?;
+        if !has_codec {
+            conn.execute(
+                "ALTER TABLE blobs ADD COLUMN codec TINYINT NOT NULL DEFAULT 0",
+                (),
+            )?;
+        }
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS chunks (
+                 chunk_id BLOB NOT NULL,
+                 data BLOB NOT NULL,
+                 codec TINYINT NOT NULL,  -- see compress::Codec
+                 PRIMARY KEY (chunk_id))",
+            (),
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS blob_chunks (
+                 blob_id BLOB NOT NULL,
+                 offset INTEGER NOT NULL,
+                 len INTEGER NOT NULL,
+                 chunk_id BLOB NOT NULL,
+                 PRIMARY KEY (blob_id, offset))",
+            (),
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS outboards (
+                 blob_id BLOB NOT NULL,
+                 data BLOB NOT NULL,  -- see outboard.rs
                  PRIMARY KEY (blob_id))",
             (),
         )?;
@@ -136,7 +239,64 @@ impl TreeDb {
                 PRIMARY KEY (tree_id, child_name))",
             (),
         )?;
-        Ok(Self { blobs_dir, conn })
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS actions (
+                action_key BLOB NOT NULL,
+                output_tree BLOB NOT NULL,
+                exit_code INTEGER NOT NULL,
+                stdout BLOB NOT NULL,
+                stderr BLOB NOT NULL,
+                PRIMARY KEY (action_key))",
+            (),
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS recent (
+                 id BLOB NOT NULL,  -- a blob or tree, see gc::mark_recent
+                 inserted_at INTEGER NOT NULL,  -- Unix time in seconds
+                 PRIMARY KEY (id))",
+            (),
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS refs (
+                name TEXT NOT NULL,
+                tree_id BLOB NOT NULL,
+                PRIMARY KEY (name))",
+            (),
+        )?;
+        Ok(Self {
+            blobs_dir,
+            conn,
+            compression: None,
+            chunking: false,
+            gc_grace_period: DEFAULT_GC_GRACE_PERIOD,
+        })
+    }
+
+    /// Sets the zstd compression level for blobs inserted from now on, or `None` (the default) to
+    /// store them uncompressed. Blob IDs are always the hash of the uncompressed contents, and
+    /// blobs are only stored compressed if that makes them smaller. Reads decompress
+    /// transparently regardless of this setting, so compressed and uncompressed blobs can live in
+    /// the same database.
+    pub fn set_compression(&mut self, level: Option<i32>) {
+        self.compression = level;
+    }
+
+    /// Sets whether large blobs inserted from now on are split into content-defined chunks,
+    /// rather than stored as whole files in the blobs dir. The default is false. Chunks are
+    /// shared between blobs, so near-identical large blobs mostly share storage. Blob IDs are
+    /// still the hash of the whole contents, and reads reassemble chunked blobs transparently
+    /// regardless of this setting. If compression is also enabled, each chunk is compressed
+    /// separately.
+    pub fn set_chunking(&mut self, enabled: bool) {
+        self.chunking = enabled;
+    }
+
+    /// Sets how long [`gc`](Self::gc) keeps blobs and trees after they're inserted, even if
+    /// nothing links to them yet. The default is an hour. This covers writers that insert their
+    /// blobs first and the trees that link them later, such as a checkout that's being snapshotted
+    /// piece by piece. Inserting an object that already exists restarts its grace period too.
+    pub fn set_gc_grace_period(&mut self, period: Duration) {
+        self.gc_grace_period = period;
     }
 
     pub fn contains_blob(&self, blob_id: blake3::Hash) -> anyhow::Result<bool> {
@@ -155,8 +315,13 @@ impl TreeDb {
     }
 
     pub fn insert_blob(&mut self, blob: &[u8]) -> anyhow::Result<blake3::Hash> {
-        // Do this first to avoid borrowck errors.
-        let blob_id = blake3::hash(blob);
+        // Do this first to avoid borrowck errors. Large blobs get an outboard for read_range(),
+        // which is computed along with the ID, before taking the lock.
+        let (blob_id, outboard) = if blob.len() < LARGE_BLOB_THRESHOLD {
+            (blake3::hash(blob), Vec::new())
+        } else {
+            outboard::encode(blob, blob.len() as u64)?
+        };
         let blob_path = self.blob_path(&blob_id);
 
         // Deferred transactions are vulnerable to BUSY errors if there are concurrent writers.
@@ -170,33 +335,48 @@ impl TreeDb {
             |row| row.get(0),
         )?;
         assert!(exists <= 1);
+        // Either way, gc() keeps the blob for a while, so the caller has time to link it.
+        gc::mark_recent(&tx, &blob_id)?;
         let exists = exists == 1;
         if exists {
+            tx.commit()?;
             return Ok(blob_id);
         }
 
         // Small blobs go in the blobs table.
         if blob.len() < LARGE_BLOB_THRESHOLD {
+            let (data, codec) = compress::encode_bytes(blob, self.compression)?;
             tx.execute(
-                "INSERT INTO blobs (blob_id, data) VALUES (?, ?)",
-                (blob_id.as_bytes(), blob),
+                "INSERT INTO blobs (blob_id, data, codec) VALUES (?, ?, ?)",
+                (blob_id.as_bytes(), &data, codec.to_column()),
             )?;
             tx.commit()?;
             return Ok(blob_id);
         }
 
-        // Large blobs go in the blobs dir.
+        // Large blobs are either chunked, or they go in the blobs dir.
+        outboard::insert_outboard(&tx, &blob_id, &outboard)?;
+        if self.chunking {
+            chunk::insert_chunks(&tx, &blob_id, blob, self.compression)?;
+            tx.execute(
+                "INSERT INTO blobs (blob_id, data, codec) VALUES (?, NULL, ?)",
+                (blob_id.as_bytes(), Codec::Chunked.to_column()),
+            )?;
+            tx.commit()?;
+            return Ok(blob_id);
+        }
+        let (data, codec) = compress::encode_bytes(blob, self.compression)?;
         tx.execute(
             // NULL data means the data is in the blobs dir. Note that this write won't be
             // observable to concurrent readers until we commit.
-            "INSERT INTO blobs (blob_id, data) VALUES (?, NULL)",
-            (blob_id.as_bytes(),),
+            "INSERT INTO blobs (blob_id, data, codec) VALUES (?, NULL, ?)",
+            (blob_id.as_bytes(), codec.to_column()),
         )?;
         // The IMMEDIATE mode transaction above should exclude any other writers, so we don't need
         // to create a randomly-named tempfile and atomically rename it.
         let mut file = File::create(&blob_path)
             .with_context(|| format!("creating file at {}", blob_path.to_string_lossy()))?;
-        file.write_all(blob)?;
+        file.write_all(&data)?;
         // Commit!
         tx.commit()?;
         // Finally, make the file read-only. If anything fails before this, a later File::create
@@ -236,17 +416,11 @@ impl TreeDb {
         }
 
         // Large blobs go in the blobs dir. Hash the file first to avoid an expensive copy if it's
-        // a duplicate. We'll trust the mtime (and on Unix, the inode) of the source file and bail
-        // if it changes across the whole hash+copy operation.
-        let blob_id = blake3::Hasher::new()
-            .update_mmap_rayon(&source_path)
-            .with_context(|| {
-                format!(
-                    "failed to hash file at {}",
-                    source_path.as_ref().to_string_lossy()
-                )
-            })?
-            .finalize();
+        // a duplicate, and compute its outboard for read_range() in the same pass. We'll trust the
+        // mtime (and on Unix, the inode) of the source file and bail if it changes across the
+        // whole hash+copy operation.
+        let (blob_id, outboard) =
+            outboard::encode_file(source_path.as_ref(), metadata_before.len())?;
         let blob_path = self.blob_path(&blob_id);
 
         // Deferred transactions are vulnerable to BUSY errors if there are concurrent writers.
@@ -260,29 +434,36 @@ impl TreeDb {
             |row| row.get(0),
         )?;
         assert!(exists <= 1);
+        // Either way, gc() keeps the blob for a while, so the caller has time to link it.
+        gc::mark_recent(&tx, &blob_id)?;
         let exists = exists == 1;
         if exists {
+            tx.commit()?;
             return Ok(blob_id);
         }
 
+        // Copy the file into the blobs dir. Use a cheap reflink if possible on filesystems that
+        // support it, e.g. BTRFS, unless compression is enabled and helps. The IMMEDIATE mode
+        // transaction above should exclude any other writers, so we don't need to create a
+        // randomly-named tempfile and atomically rename it. If chunking is enabled, read the file
+        // into chunks instead.
+        let codec = if self.chunking {
+            let reader = (&source_file).take(metadata_before.len());
+            chunk::insert_chunks(&tx, &blob_id, reader, self.compression)?;
+            Codec::Chunked
+        } else {
+            compress::store_file(source_path.as_ref(), &blob_path, self.compression)?
+        };
+
+        outboard::insert_outboard(&tx, &blob_id, &outboard)?;
+
         // NULL data means the data is in the blobs dir. Note that this write won't be observable
         // to concurrent readers until we commit.
         tx.execute(
-            "INSERT INTO blobs (blob_id, data) VALUES (?, NULL)",
-            (blob_id.as_bytes(),),
+            "INSERT INTO blobs (blob_id, data, codec) VALUES (?, NULL, ?)",
+            (blob_id.as_bytes(), codec.to_column()),
         )?;
 
-        // Copy the file into the blobs dir. Use a cheap reflink if possible on filesystems that
-        // support it, e.g. BTRFS. The IMMEDIATE mode transaction above should exclude any other
-        // writers, so we don't need to create a randomly-named tempfile and atomically rename it.
-        reflink_copy::reflink_or_copy(&source_path, &blob_path).with_context(|| {
-            format!(
-                "failed to copy {} to {}",
-                source_path.as_ref().to_string_lossy(),
-                blob_path.to_string_lossy(),
-            )
-        })?;
-
         // Double check the mtime and (on Unix) inode of the original file, to guard against FS
         // races. You can spoof mtime if you want to, so this isn't bulletproof, but at that point
         // you deserve what you get. (You can also just corrupt the blobs dir yourself if you feel
@@ -309,10 +490,13 @@ impl TreeDb {
 
         // Finally, make the copied file read-only. If anything fails before this, a later
         // File::create operation can silently overwrite this file and recover.
+        if codec == Codec::Chunked {
+            return Ok(blob_id);
+        }
         let copied_file = File::open(&blob_path)?;
         let mut permissions = copied_file.metadata()?.permissions();
         permissions.set_readonly(true);
-        source_file.set_permissions(permissions)?;
+        copied_file.set_permissions(permissions)?;
         Ok(blob_id)
     }
 
@@ -320,24 +504,30 @@ impl TreeDb {
     pub fn get_blob(&mut self, blob_id: &blake3::Hash) -> anyhow::Result<Vec<u8>> {
         // If there is no row, the blob doesn't exist. If there is a row but it has NULL data, the
         // data is in the blobs dir.
-        let row: Option<Option<Vec<u8>>> = self
+        let row: Option<(Option<Vec<u8>>, u8)> = self
             .conn
             .query_row(
-                "SELECT data FROM blobs WHERE blob_id = ?",
+                "SELECT data, codec FROM blobs WHERE blob_id = ?",
                 (blob_id.as_bytes(),),
-                |row| row.get(0),
+                |row| Ok((row.get(0)?, row.get(1)?)),
             )
             .optional()?;
         match row {
             // Blob doesn't exist.
             None => bail!("blob {} doesn't exist", blob_id),
             // Data was in the blobs table.
-            Some(Some(v)) => Ok(v),
-            // Data is in the blobs dir.
-            Some(None) => {
-                let data = fs::read(self.blob_path(&blob_id))?;
+            Some((Some(v), codec)) => compress::decode_bytes(v, Codec::from_column(codec)?),
+            // Data is in the chunks table.
+            Some((None, codec)) if Codec::from_column(codec)? == Codec::Chunked => {
+                let mut data = Vec::new();
+                self.open_blob(blob_id)?.read_to_end(&mut data)?;
                 Ok(data)
             }
+            // Data is in the blobs dir.
+            Some((None, codec)) => {
+                let data = fs::read(self.blob_path(blob_id))?;
+                compress::decode_bytes(data, Codec::from_column(codec)?)
+            }
         }
     }
 
@@ -349,19 +539,20 @@ impl TreeDb {
     ) -> anyhow::Result<()> {
         // If there is no row, the blob doesn't exist. If there is a row but it has NULL data, the
         // data is in the blobs dir.
-        let row: Option<Option<Vec<u8>>> = self
+        let row: Option<(Option<Vec<u8>>, u8)> = self
             .conn
             .query_row(
-                "SELECT data FROM blobs WHERE blob_id = ?",
+                "SELECT data, codec FROM blobs WHERE blob_id = ?",
                 (blob_id.as_bytes(),),
-                |row| row.get(0),
+                |row| Ok((row.get(0)?, row.get(1)?)),
             )
             .optional()?;
         match row {
             // Blob doesn't exist.
             None => bail!("blob {} doesn't exist", blob_id),
             // Data was in the blobs table.
-            Some(Some(v)) => {
+            Some((Some(v), codec)) => {
+                let v = compress::decode_bytes(v, Codec::from_column(codec)?)?;
                 if let Some(parent_dir) = destination.as_ref().parent() {
                     // Automatically create any parent directories.
                     fs::create_dir_all(parent_dir).with_context(|| {
@@ -371,8 +562,8 @@ impl TreeDb {
                 fs::write(destination, &v)?;
                 Ok(())
             }
-            // Data is in the blobs dir. Reflink it if possible.
-            Some(None) => {
+            // Data is in the blobs dir. Reflink it if possible, or decompress it if it's compressed.
+            Some((None, codec)) => {
                 let source = self.blob_path(blob_id);
                 if fs::exists(&destination)? {
                     // reflink_or_copy() requires the destination to be clear.
@@ -388,13 +579,40 @@ impl TreeDb {
                         format!("creating directory {}", parent_dir.to_string_lossy())
                     })?;
                 }
-                reflink_copy::reflink_or_copy(&source, &destination).with_context(|| {
-                    format!(
-                        "copying {} to {}",
-                        source.to_string_lossy(),
-                        destination.as_ref().to_string_lossy(),
-                    )
-                })?;
+                match Codec::from_column(codec)? {
+                    Codec::None => {
+                        reflink_copy::reflink_or_copy(&source, &destination).with_context(
+                            || {
+                                format!(
+                                    "copying {} to {}",
+                                    source.to_string_lossy(),
+                                    destination.as_ref().to_string_lossy(),
+                                )
+                            },
+                        )?;
+                    }
+                    Codec::Chunked => {
+                        let mut file = File::create(&destination)?;
+                        io::copy(&mut self.open_blob(blob_id)?, &mut file).with_context(|| {
+                            format!(
+                                "reassembling {} to {}",
+                                blob_id,
+                                destination.as_ref().to_string_lossy(),
+                            )
+                        })?;
+                    }
+                    codec => {
+                        let mut decoder = compress::decoder(File::open(&source)?, codec)?;
+                        let mut file = File::create(&destination)?;
+                        io::copy(&mut decoder, &mut file).with_context(|| {
+                            format!(
+                                "decompressing {} to {}",
+                                source.to_string_lossy(),
+                                destination.as_ref().to_string_lossy(),
+                            )
+                        })?;
+                    }
+                }
                 Ok(())
             }
         }
@@ -414,14 +632,11 @@ impl TreeDb {
         })?;
         for row in rows {
             let (child_name, child_id, node_type, executable) = row?;
-            let node_type = match (node_type, executable) {
-                (0, _) => NodeType::Blob { executable },
-                (1, false) => NodeType::Tree,
-                _ => bail!("unknown node type: {} {}", node_type, executable),
-            };
+            let node_type = parse_node_type(node_type, executable)?;
             tree.add_child(child_name, &child_id.into(), node_type);
         }
-        if tree.len() > 0 {
+        // A tree with no rows is either the empty tree or unknown.
+        if !tree.is_empty() || *tree_id == Tree::new().id() {
             Ok(Some(tree))
         } else {
             Ok(None)
@@ -429,39 +644,80 @@ impl TreeDb {
     }
 
     pub fn insert_tree(&mut self, tree: &Tree) -> anyhow::Result<blake3::Hash> {
-        assert_ne!(tree.len(), 0, "can't insert empty trees");
-        let tree_id = tree.id();
-        let tx = self.conn.transaction()?;
-        for child in tree.iter() {
-            match child.node_type {
-                NodeType::Blob { .. } => {
-                    let blob_count: u64 = tx.query_row(
-                        "SELECT COUNT(*) FROM blobs WHERE blob_id = ?",
-                        (child.id.as_bytes(),),
-                        |row| row.get(0),
-                    )?;
-                    assert!(blob_count <= 1, "duplicate blobs?");
-                    ensure!(blob_count == 1, "blob {} does not exist", child.id);
-                }
-                NodeType::Tree { .. } => {
-                    let tree_count: u64 = tx.query_row(
-                        "SELECT COUNT(*) FROM trees WHERE tree_id = ?",
-                        (child.id.as_bytes(),),
-                        |row| row.get(0),
-                    )?;
-                    ensure!(tree_count > 0, "tree {} does not exist", child.id);
-                }
-            }
-            let (node_type, executable) = match child.node_type {
-                NodeType::Blob { executable } => (0u8, executable),
-                NodeType::Tree => (1u8, false),
-            };
-            tx.execute(
-                "INSERT INTO trees (tree_id, child_name, child_id, node_type, executable) VALUES (?, ?, ?, ?, ?)",
-                (tree_id.as_bytes(), child.name, child.id.as_bytes(), node_type, executable),
-            )?;
-        }
+        // Use an IMMEDIATE transaction so that checking that the children exist and inserting the
+        // rows happen atomically with respect to gc().
+        let tx = self.conn.transaction_with_behavior(Immediate)?;
+        let tree_id = insert_tree_rows(&tx, tree)?;
         tx.commit()?;
         Ok(tree_id)
     }
 }
+
+/// The inverse of the `(node_type, executable)` mapping in `insert_tree_rows`.
+fn parse_node_type(node_type: u8, executable: bool) -> anyhow::Result<NodeType> {
+    Ok(match (node_type, executable) {
+        (0, _) => NodeType::Blob { executable },
+        (1, false) => NodeType::Tree,
+        (2, false) => NodeType::Symlink,
+        _ => bail!("unknown node type: {} {}", node_type, executable),
+    })
+}
+
+/// Trees are stored as one row per child, so the empty tree has no rows. Instead, it implicitly
+/// always exists.
+fn tree_exists(conn: &rusqlite::Connection, tree_id: &blake3::Hash) -> anyhow::Result<bool> {
+    if *tree_id == Tree::new().id() {
+        return Ok(true);
+    }
+    let tree_count: u64 = conn.query_row(
+        "SELECT COUNT(*) FROM trees WHERE tree_id = ?",
+        (tree_id.as_bytes(),),
+        |row| row.get(0),
+    )?;
+    Ok(tree_count > 0)
+}
+
+/// Inserts the rows for `tree`, after checking that all of its children exist. The caller is
+/// responsible for the transaction. Trees that already exist are skipped.
+fn insert_tree_rows(conn: &rusqlite::Connection, tree: &Tree) -> anyhow::Result<blake3::Hash> {
+    let tree_id = tree.id();
+
+    // Short-circuit if this tree already exists. Without this, inserting the same tree twice
+    // would violate the primary key. This also covers the empty tree, which has no rows. Either
+    // way, gc() keeps the tree and everything under it for a while.
+    gc::mark_recent(conn, &tree_id)?;
+    if tree_exists(conn, &tree_id)? {
+        return Ok(tree_id);
+    }
+
+    for child in tree.iter() {
+        match child.node_type {
+            NodeType::Blob { .. } | NodeType::Symlink => {
+                let blob_count: u64 = conn.query_row(
+                    "SELECT COUNT(*) FROM blobs WHERE blob_id = ?",
+                    (child.id.as_bytes(),),
+                    |row| row.get(0),
+                )?;
+                assert!(blob_count <= 1, "duplicate blobs?");
+                ensure!(blob_count == 1, "blob {} does not exist", child.id);
+            }
+            NodeType::Tree => {
+                ensure!(
+                    tree_exists(conn, child.id)?,
+                    "tree {} does not exist",
+                    child.id
+                );
+            }
+        }
+        let (node_type, executable) = match child.node_type {
+            NodeType::Blob { executable } => (0u8, executable),
+            NodeType::Tree => (1u8, false),
+            NodeType::Symlink => (2u8, false),
+        };
+        conn.execute(
+            "INSERT INTO trees (tree_id, child_name, child_id, node_type, executable) VALUES (?, ?, ?, ?, ?)",
+            (tree_id.as_bytes(), child.name, child.id.as_bytes(), node_type, executable),
+        )?;
+    }
+    Ok(tree_id)
+}
@@ -0,0 +1,157 @@
+use anyhow::{Context, bail};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, prelude::*};
+use std::path::PathBuf;
+use treedb::TreeDb;
+
+const USAGE: &str = "\
+Usage: treedb --store PATH COMMAND [ARGS]
+
+Commands:
+  put-blob [FILE]         Insert FILE, or stdin, as a blob and print its ID
+  put-file FILE           Insert FILE as a blob, reflinking it if it's large, and print its ID
+  cat-blob ID             Write a blob to stdout
+  get-file ID DEST        Copy a blob to the file DEST
+  ls-tree [-r] ID         List a tree's entries, or with -r all the files under it
+  put-tree [MANIFEST]     Insert a tree listed in ls-tree's format, from MANIFEST or stdin, and
+                          print its ID
+  stats                   Print object counts and sizes
+  verify [--repair]       Check every object, and with --repair quarantine the bad ones
+
+Each line of ls-tree's output is `TYPE ID<tab>PATH`, where TYPE is blob, exec, symlink, or tree.
+IDs are printed in hex. Backslashes, newlines, and carriage returns in PATH are escaped as
+\\\\, \\n, and \\r.";
+
+const COMMANDS: &[&str] = &[
+    "put-blob", "put-file", "cat-blob", "get-file", "ls-tree", "put-tree", "stats", "verify",
+];
+
+fn parse_id(hex: &str) -> anyhow::Result<blake3::Hash> {
+    blake3::Hash::from_hex(hex).with_context(|| format!("invalid ID {:?}", hex))
+}
+
+fn input(path: Option<&String>) -> anyhow::Result<Box<dyn Read>> {
+    Ok(match path {
+        Some(path) => {
+            Box::new(File::open(path).with_context(|| format!("failed to open {}", path))?)
+        }
+        None => Box::new(io::stdin().lock()),
+    })
+}
+
+fn run(args: &[String]) -> anyhow::Result<()> {
+    let mut store = None;
+    let mut rest = args;
+    while let Some(arg) = rest.first() {
+        if arg == "-h" || arg == "--help" {
+            println!("{}", USAGE);
+            return Ok(());
+        } else if let Some(path) = arg.strip_prefix("--store=") {
+            store = Some(PathBuf::from(path));
+            rest = &rest[1..];
+        } else if arg == "--store" {
+            let Some(path) = rest.get(1) else {
+                bail!("--store needs a path");
+            };
+            store = Some(PathBuf::from(path));
+            rest = &rest[2..];
+        } else {
+            break;
+        }
+    }
+    let Some((command, command_args)) = rest.split_first() else {
+        bail!("no command given\n\n{}", USAGE);
+    };
+    let Some(store) = store else {
+        bail!("--store is required\n\n{}", USAGE);
+    };
+    let wrong_args = || anyhow::anyhow!("wrong arguments for {}\n\n{}", command, USAGE);
+    let mut db = TreeDb::open(&store)
+        .with_context(|| format!("failed to open store {}", store.to_string_lossy()))?;
+    let mut stdout = BufWriter::new(io::stdout().lock());
+
+    match (command.as_str(), command_args) {
+        ("put-blob", [] | [_]) => {
+            let mut writer = db.blob_writer();
+            io::copy(&mut input(command_args.first())?, &mut writer)?;
+            writeln!(stdout, "{}", writer.finish()?.to_hex())?;
+        }
+        ("put-file", [path]) => {
+            writeln!(stdout, "{}", db.insert_file(path)?.to_hex())?;
+        }
+        ("cat-blob", [id]) => {
+            io::copy(&mut db.open_blob(&parse_id(id)?)?, &mut stdout)?;
+        }
+        ("get-file", [id, destination]) => {
+            db.get_file(&parse_id(id)?, destination)?;
+        }
+        ("ls-tree", [id]) => {
+            db.write_manifest(&parse_id(id)?, false, &mut stdout)?;
+        }
+        ("ls-tree", [flag, id]) if flag == "-r" => {
+            db.write_manifest(&parse_id(id)?, true, &mut stdout)?;
+        }
+        ("put-tree", [] | [_]) => {
+            let manifest = BufReader::new(input(command_args.first())?);
+            writeln!(stdout, "{}", db.insert_manifest(manifest)?.to_hex())?;
+        }
+        ("stats", []) => {
+            let stats = db.stats()?;
+            writeln!(stdout, "blobs: {}", stats.blobs)?;
+            writeln!(stdout, "large blobs: {}", stats.large_blobs)?;
+            writeln!(stdout, "compressed blobs: {}", stats.compressed_blobs)?;
+            writeln!(stdout, "chunked blobs: {}", stats.chunked_blobs)?;
+            writeln!(stdout, "chunks: {}", stats.chunks)?;
+            writeln!(stdout, "trees: {}", stats.trees)?;
+            writeln!(stdout, "refs: {}", stats.refs)?;
+            writeln!(stdout, "action results: {}", stats.action_results)?;
+            writeln!(stdout, "stored bytes: {}", stats.stored_bytes)?;
+        }
+        ("verify", [] | [_]) => {
+            let repair = match command_args {
+                [] => false,
+                [flag] if flag == "--repair" => true,
+                _ => return Err(wrong_args()),
+            };
+            let report = db.verify(repair)?;
+            for id in &report.corrupt_blobs {
+                writeln!(stdout, "corrupt blob {}", id.to_hex())?;
+            }
+            for id in &report.missing_blob_files {
+                writeln!(stdout, "missing blob file {}", id.to_hex())?;
+            }
+            for id in &report.corrupt_outboards {
+                writeln!(stdout, "corrupt outboard {}", id.to_hex())?;
+            }
+            for id in &report.corrupt_trees {
+                writeln!(stdout, "corrupt tree {}", id.to_hex())?;
+            }
+            for (tree_id, name, child_id) in &report.dangling_children {
+                let (tree_id, child_id) = (tree_id.to_hex(), child_id.to_hex());
+                writeln!(stdout, "dangling child {} {:?} {}", tree_id, name, child_id)?;
+            }
+            for path in &report.orphaned_files {
+                writeln!(stdout, "orphaned file {}", path.to_string_lossy())?;
+            }
+            if let Some(dir) = &report.quarantine_dir {
+                writeln!(stdout, "quarantined to {}", dir.to_string_lossy())?;
+            }
+            stdout.flush()?;
+            if !report.is_ok() {
+                bail!("store {} has problems", store.to_string_lossy());
+            }
+        }
+        _ if COMMANDS.contains(&command.as_str()) => return Err(wrong_args()),
+        _ => bail!("unknown command {:?}\n\n{}", command, USAGE),
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("treedb: {:#}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,131 @@
+use crate::{NodeType, Tree, TreeDb, TreeEdit};
+use anyhow::{Context, bail};
+use std::io::{BufRead, Write};
+
+fn type_name(node_type: NodeType) -> &'static str {
+    match node_type {
+        NodeType::Blob { executable: false } => "blob",
+        NodeType::Blob { executable: true } => "exec",
+        NodeType::Symlink => "symlink",
+        NodeType::Tree => "tree",
+    }
+}
+
+fn parse_type(name: &str) -> anyhow::Result<NodeType> {
+    Ok(match name {
+        "blob" => NodeType::Blob { executable: false },
+        "exec" => NodeType::Blob { executable: true },
+        "symlink" => NodeType::Symlink,
+        "tree" => NodeType::Tree,
+        _ => bail!("unknown type {:?}", name),
+    })
+}
+
+// Names can contain anything but `/` and NUL, so backslashes, newlines, and carriage returns are
+// escaped to keep each entry on one line.
+fn escape_path(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape_path(escaped: &str) -> anyhow::Result<String> {
+    let mut path = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            path.push(c);
+            continue;
+        }
+        path.push(match chars.next() {
+            Some('\\') => '\\',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some(c) => bail!("unknown escape \\{} in path", c),
+            None => bail!("path ends with a backslash"),
+        });
+    }
+    Ok(path)
+}
+
+fn write_line(
+    out: &mut impl Write,
+    node_type: NodeType,
+    id: &blake3::Hash,
+    path: &str,
+) -> anyhow::Result<()> {
+    let (type_name, id, path) = (type_name(node_type), id.to_hex(), escape_path(path));
+    writeln!(out, "{} {}\t{}", type_name, id, path)?;
+    Ok(())
+}
+
+impl TreeDb {
+    /// Lists a tree as a manifest, one `TYPE ID<tab>PATH` line per entry, where TYPE is `blob`,
+    /// `exec`, `symlink`, or `tree` and ID is in hex. Backslashes, newlines, and carriage returns
+    /// in PATH are escaped as `\\`, `\n`, and `\r`.
+    ///
+    /// Without `recursive` this lists the tree's direct children. With it, every file and symlink
+    /// under the tree is listed by its full path, along with empty trees, so that
+    /// [`insert_manifest`](TreeDb::insert_manifest) can recreate the tree exactly.
+    pub fn write_manifest(
+        &mut self,
+        tree_id: &blake3::Hash,
+        recursive: bool,
+        mut out: impl Write,
+    ) -> anyhow::Result<()> {
+        if !recursive {
+            let Some(tree) = self.get_tree(tree_id)? else {
+                bail!("tree {} doesn't exist", tree_id);
+            };
+            for child in tree.iter() {
+                write_line(&mut out, child.node_type(), child.id(), child.name())?;
+            }
+            return Ok(());
+        }
+        let empty_id = Tree::new().id();
+        for entry in self.walk(tree_id)? {
+            let entry = entry?;
+            if entry.node_type != NodeType::Tree || entry.id == empty_id {
+                write_line(&mut out, entry.node_type, &entry.id, &entry.path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Inserts the tree described by a manifest in [`write_manifest`](TreeDb::write_manifest)'s
+    /// format, and returns its ID. Blank lines are skipped. Every ID must already exist, and
+    /// parent trees are created as needed.
+    pub fn insert_manifest(&mut self, manifest: impl BufRead) -> anyhow::Result<blake3::Hash> {
+        let mut edits = Vec::new();
+        for (index, line) in manifest.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let entry = || -> anyhow::Result<TreeEdit> {
+                let Some((type_and_id, path)) = line.split_once('\t') else {
+                    bail!("expected `TYPE ID<tab>PATH`");
+                };
+                let Some((node_type, id)) = type_and_id.split_once(' ') else {
+                    bail!("expected `TYPE ID<tab>PATH`");
+                };
+                Ok(TreeEdit::Set {
+                    path: unescape_path(path)?,
+                    id: blake3::Hash::from_hex(id)
+                        .with_context(|| format!("invalid ID {:?}", id))?,
+                    node_type: parse_type(node_type)?,
+                })
+            };
+            edits.push(entry().with_context(|| format!("manifest line {}", index + 1))?);
+        }
+        let empty_id = self.insert_tree(&Tree::new())?;
+        self.edit_tree(&empty_id, &edits)
+    }
+}
@@ -0,0 +1,355 @@
+use crate::{LARGE_BLOB_THRESHOLD, TreeDb};
+use anyhow::{Context, ensure};
+use blake3::hazmat::{
+    ChainingValue, HasherExt, Mode, left_subtree_len, merge_subtrees_non_root, merge_subtrees_root,
+};
+use rayon::prelude::*;
+use rusqlite::{OptionalExtension, Transaction, TransactionBehavior::Immediate};
+use std::fs::File;
+use std::io::{self, SeekFrom, prelude::*};
+use std::path::Path;
+
+// Outboards follow the Bao format, except that each leaf covers a group of 16 chunks rather than a
+// single chunk. That makes the outboard about 0.4% of the size of the blob instead of 6%, at the
+// cost of reading and hashing up to 16 KiB extra at each end of a range.
+const GROUP_LEN: u64 = 1 << 14;
+const PARENT_LEN: u64 = 64;
+
+// The number of outboard bytes for a subtree of `len` bytes: one parent node for every leaf
+// except the first.
+fn outboard_len(len: u64) -> u64 {
+    (len.div_ceil(GROUP_LEN).max(1) - 1) * PARENT_LEN
+}
+
+// Files are hashed in parallel in segments of this many groups, each read by its own thread.
+const SEGMENT_GROUPS: u64 = 64;
+
+fn leaf_cv(offset: u64, data: &[u8]) -> ChainingValue {
+    let mut hasher = blake3::Hasher::new();
+    hasher.set_input_offset(offset);
+    hasher.update(data);
+    hasher.finalize_non_root()
+}
+
+// Builds the two children of a subtree of `len` bytes from the chaining values of its leaves,
+// appending their parent nodes to `outboard` in pre-order. The caller has already reserved space
+// for the subtree's own parent node, at `parent_pos`.
+fn build_children(
+    leaves: &[ChainingValue],
+    len: u64,
+    outboard: &mut Vec<u8>,
+    parent_pos: usize,
+) -> (ChainingValue, ChainingValue) {
+    // Subtrees bigger than a group are always a whole number of groups on the left.
+    let left_len = left_subtree_len(len);
+    let (left_leaves, right_leaves) = leaves.split_at((left_len / GROUP_LEN) as usize);
+    let left = build_subtree(left_leaves, left_len, outboard);
+    let right = build_subtree(right_leaves, len - left_len, outboard);
+    outboard[parent_pos..][..32].copy_from_slice(&left);
+    outboard[parent_pos + 32..][..32].copy_from_slice(&right);
+    (left, right)
+}
+
+fn build_subtree(leaves: &[ChainingValue], len: u64, outboard: &mut Vec<u8>) -> ChainingValue {
+    if len <= GROUP_LEN {
+        return leaves[0];
+    }
+    let parent_pos = outboard.len();
+    outboard.resize(parent_pos + PARENT_LEN as usize, 0);
+    let (left, right) = build_children(leaves, len, outboard, parent_pos);
+    merge_subtrees_non_root(&left, &right, Mode::Hash)
+}
+
+// Builds the outboard of a blob of `len` bytes, which must be more than one group, from the
+// chaining values of its leaves. Returns the root hash too.
+fn build_outboard(leaves: &[ChainingValue], len: u64) -> (blake3::Hash, Vec<u8>) {
+    debug_assert_eq!(leaves.len() as u64, len.div_ceil(GROUP_LEN));
+    let mut outboard = vec![0; PARENT_LEN as usize];
+    let (left, right) = build_children(leaves, len, &mut outboard, 0);
+    debug_assert_eq!(outboard.len() as u64, outboard_len(len));
+    (merge_subtrees_root(&left, &right, Mode::Hash), outboard)
+}
+
+/// Computes a blob's ID and its outboard in one pass, as the blob is written to it. The root hash
+/// is the same as [`blake3::hash`], so this can replace a plain hasher.
+pub(crate) struct OutboardEncoder {
+    // The hasher for the group that's being filled.
+    group: blake3::Hasher,
+    group_len: u64,
+    len: u64,
+    leaves: Vec<ChainingValue>,
+}
+
+impl OutboardEncoder {
+    pub(crate) fn new() -> Self {
+        Self {
+            group: blake3::Hasher::new(),
+            group_len: 0,
+            len: 0,
+            leaves: Vec::new(),
+        }
+    }
+
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // A full group is only finished once more data arrives, since a blob that's a single
+            // group hashes it as the root instead.
+            if self.group_len == GROUP_LEN {
+                self.leaves.push(self.group.finalize_non_root());
+                self.group = blake3::Hasher::new();
+                self.group.set_input_offset(self.len);
+                self.group_len = 0;
+            }
+            let n = data.len().min((GROUP_LEN - self.group_len) as usize);
+            self.group.update(&data[..n]);
+            self.group_len += n as u64;
+            self.len += n as u64;
+            data = &data[n..];
+        }
+    }
+
+    /// Returns the root hash and the outboard.
+    pub(crate) fn finalize(mut self) -> (blake3::Hash, Vec<u8>) {
+        if self.leaves.is_empty() {
+            return (self.group.finalize(), Vec::new());
+        }
+        self.leaves.push(self.group.finalize_non_root());
+        build_outboard(&self.leaves, self.len)
+    }
+}
+
+impl Write for OutboardEncoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Computes the outboard of a blob of `len` bytes, read from `reader`. Returns the root hash too,
+/// so that the caller can check it against the blob ID.
+pub(crate) fn encode(reader: impl Read, len: u64) -> anyhow::Result<(blake3::Hash, Vec<u8>)> {
+    let mut encoder = OutboardEncoder::new();
+    let copied = io::copy(&mut reader.take(len), &mut encoder)?;
+    ensure!(copied == len, "expected {} bytes but found {}", len, copied);
+    Ok(encoder.finalize())
+}
+
+/// Like [`encode`], but for the first `len` bytes of the file at `path`, which are hashed in
+/// parallel.
+pub(crate) fn encode_file(path: &Path, len: u64) -> anyhow::Result<(blake3::Hash, Vec<u8>)> {
+    if len <= GROUP_LEN {
+        return encode(File::open(path)?, len);
+    }
+    let segment_len = SEGMENT_GROUPS * GROUP_LEN;
+    let segments: Vec<Vec<ChainingValue>> = (0..len.div_ceil(segment_len))
+        .into_par_iter()
+        .map(|segment| -> anyhow::Result<_> {
+            let offset = segment * segment_len;
+            let mut data = vec![0; segment_len.min(len - offset) as usize];
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut data)?;
+            Ok(data
+                .chunks(GROUP_LEN as usize)
+                .enumerate()
+                .map(|(i, group)| leaf_cv(offset + i as u64 * GROUP_LEN, group))
+                .collect())
+        })
+        .collect::<anyhow::Result<_>>()
+        .with_context(|| format!("failed to hash file at {}", path.to_string_lossy()))?;
+    Ok(build_outboard(&segments.concat(), len))
+}
+
+/// Inserts an outboard computed by [`OutboardEncoder`] or [`encode`]. The caller is responsible
+/// for the transaction, and for checking that the outboard's root hash is the blob ID.
+pub(crate) fn insert_outboard(
+    conn: &rusqlite::Connection,
+    blob_id: &blake3::Hash,
+    outboard: &[u8],
+) -> anyhow::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO outboards (blob_id, data) VALUES (?, ?)",
+        (blob_id.as_bytes(), outboard),
+    )?;
+    Ok(())
+}
+
+// Walks down the outboard tree to the leaves that overlap `start..end`, verifying every parent
+// node and leaf along the way, and appends the overlapping bytes to `output`.
+struct RangeVerifier<'a, R> {
+    reader: R,
+    outboard: &'a [u8],
+    start: u64,
+    end: u64,
+    output: Vec<u8>,
+}
+
+impl<R: Read + Seek> RangeVerifier<'_, R> {
+    fn parent(&self, pos: u64) -> (ChainingValue, ChainingValue) {
+        let node = &self.outboard[pos as usize..][..PARENT_LEN as usize];
+        (
+            node[..32].try_into().unwrap(),
+            node[32..].try_into().unwrap(),
+        )
+    }
+
+    fn read_leaf(&mut self, offset: u64, len: u64) -> anyhow::Result<Vec<u8>> {
+        let mut data = vec![0; len as usize];
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn append_overlap(&mut self, offset: u64, data: &[u8]) {
+        let from = self.start.max(offset) - offset;
+        let to = self.end.min(offset + data.len() as u64) - offset;
+        self.output
+            .extend_from_slice(&data[from as usize..to as usize]);
+    }
+
+    fn verify_children(
+        &mut self,
+        offset: u64,
+        len: u64,
+        pos: u64,
+        (left, right): (ChainingValue, ChainingValue),
+    ) -> anyhow::Result<()> {
+        let left_len = left_subtree_len(len);
+        if offset < self.end && self.start < offset + left_len {
+            self.verify_subtree(offset, left_len, pos + PARENT_LEN, &left)?;
+        }
+        let right_offset = offset + left_len;
+        if right_offset < self.end && self.start < offset + len {
+            let right_pos = pos + PARENT_LEN + outboard_len(left_len);
+            self.verify_subtree(right_offset, len - left_len, right_pos, &right)?;
+        }
+        Ok(())
+    }
+
+    fn verify_subtree(
+        &mut self,
+        offset: u64,
+        len: u64,
+        pos: u64,
+        expected: &ChainingValue,
+    ) -> anyhow::Result<()> {
+        if len <= GROUP_LEN {
+            let data = self.read_leaf(offset, len)?;
+            ensure!(
+                leaf_cv(offset, &data) == *expected,
+                "bytes {}..{} don't match the outboard",
+                offset,
+                offset + len,
+            );
+            self.append_overlap(offset, &data);
+            return Ok(());
+        }
+        let children = self.parent(pos);
+        ensure!(
+            merge_subtrees_non_root(&children.0, &children.1, Mode::Hash) == *expected,
+            "outboard is corrupt at offset {}",
+            pos,
+        );
+        self.verify_children(offset, len, pos, children)
+    }
+}
+
+impl TreeDb {
+    /// Reads `len` bytes at `offset` from a blob, verifying them against the blob ID without
+    /// reading the rest of the blob. Returns an error if the blob doesn't exist, if the range is
+    /// out of bounds, or if the data is corrupt.
+    ///
+    /// Large blobs keep a [Bao](https://github.com/oconnor663/bao) outboard tree, which holds the
+    /// BLAKE3 chaining values needed to verify any range by itself. Outboards are computed when
+    /// blobs are inserted. Blobs inserted before outboards were supported get one the first time
+    /// they're read here, which requires hashing the whole blob once.
+    pub fn read_range(
+        &self,
+        blob_id: &blake3::Hash,
+        offset: u64,
+        len: u64,
+    ) -> anyhow::Result<Vec<u8>> {
+        let blob_len = self.open_blob(blob_id)?.len();
+        let end = offset
+            .checked_add(len)
+            .filter(|&end| end <= blob_len)
+            .with_context(|| {
+                format!(
+                    "range {}+{} is out of bounds for blob {} of length {}",
+                    offset, len, blob_id, blob_len,
+                )
+            })?;
+
+        // Small blobs are cheap enough to verify in full.
+        if blob_len < LARGE_BLOB_THRESHOLD as u64 {
+            let mut data = Vec::new();
+            self.open_blob(blob_id)?.read_to_end(&mut data)?;
+            ensure!(
+                blake3::hash(&data) == *blob_id,
+                "blob {} doesn't match its contents",
+                blob_id,
+            );
+            return Ok(data[offset as usize..end as usize].to_vec());
+        }
+
+        let outboard = match self.get_outboard(blob_id)? {
+            Some(outboard) => outboard,
+            None => {
+                let (root, outboard) = encode(self.open_blob(blob_id)?, blob_len)?;
+                ensure!(
+                    root == *blob_id,
+                    "blob {} doesn't match its contents",
+                    blob_id,
+                );
+                // This only takes &self, so it can't use transaction_with_behavior(), but it
+                // still takes the write lock up front like every other write. Skip the insert if
+                // gc() deleted the blob in the meantime.
+                let tx = Transaction::new_unchecked(&self.conn, Immediate)?;
+                tx.execute(
+                    "INSERT OR REPLACE INTO outboards (blob_id, data)
+                     SELECT blob_id, ? FROM blobs WHERE blob_id = ?",
+                    (&outboard, blob_id.as_bytes()),
+                )?;
+                tx.commit()?;
+                outboard
+            }
+        };
+        ensure!(
+            outboard.len() as u64 == outboard_len(blob_len),
+            "outboard for blob {} has the wrong length",
+            blob_id,
+        );
+        let mut verifier = RangeVerifier {
+            reader: self.open_blob(blob_id)?,
+            outboard: &outboard,
+            start: offset,
+            end,
+            output: Vec::with_capacity(len as usize),
+        };
+        if len > 0 {
+            let children = verifier.parent(0);
+            ensure!(
+                merge_subtrees_root(&children.0, &children.1, Mode::Hash) == *blob_id,
+                "outboard for blob {} doesn't match its ID",
+                blob_id,
+            );
+            verifier.verify_children(0, blob_len, 0, children)?;
+        }
+        Ok(verifier.output)
+    }
+
+    fn get_outboard(&self, blob_id: &blake3::Hash) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT data FROM outboards WHERE blob_id = ?",
+                (blob_id.as_bytes(),),
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+}
@@ -0,0 +1,314 @@
+use crate::diff::join_path;
+use crate::{NodeType, Tree, TreeDb, insert_tree_rows, parse_node_type, tree_exists};
+use anyhow::{bail, ensure};
+use rusqlite::{OptionalExtension, TransactionBehavior::Immediate};
+use std::collections::BTreeMap;
+
+/// One operation for [`TreeDb::edit_tree`]. Paths are separated by `/`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TreeEdit {
+    /// Adds or replaces the entry at `path`, creating any missing parent trees. The object must
+    /// already exist.
+    Set {
+        path: String,
+        id: blake3::Hash,
+        node_type: NodeType,
+    },
+    /// Removes the entry at `path`, which must exist. Its parent is left in place even if it
+    /// becomes empty.
+    Remove { path: String },
+    /// Moves the entry at `from`, which must exist, to `to`, replacing anything already there.
+    Rename { from: String, to: String },
+    /// Merges the tree `id` into the tree at `path`, creating any missing parent trees. Where
+    /// both have a tree with the same name, those get merged too, and otherwise entries from `id`
+    /// replace existing ones. If there's no tree at `path`, this is the same as `Set`. An empty
+    /// path merges into the root.
+    Merge { path: String, id: blake3::Hash },
+}
+
+// The in-memory state of an edit. Only the trees along the edited paths get loaded, and every
+// other entry stays as an ID that gets reused as-is.
+pub(crate) enum EditNode {
+    Unloaded(blake3::Hash, NodeType),
+    Loaded(BTreeMap<String, EditNode>),
+}
+
+impl EditNode {
+    fn is_tree(&self) -> bool {
+        matches!(
+            self,
+            EditNode::Loaded(_) | EditNode::Unloaded(_, NodeType::Tree)
+        )
+    }
+}
+
+// Splits a `/`-separated path into its components, ignoring empty ones, so that leading, trailing,
+// and repeated slashes are allowed.
+pub(crate) fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|component| !component.is_empty())
+}
+
+fn path_components(path: &str) -> anyhow::Result<(Vec<&str>, &str)> {
+    let mut components: Vec<&str> = split_path(path).collect();
+    let Some(last) = components.pop() else {
+        bail!("can't edit the root itself");
+    };
+    for component in components.iter().chain([&last]) {
+        ensure!(
+            !component.contains('\0'),
+            "path {:?} contains a null byte",
+            path
+        );
+    }
+    Ok((components, last))
+}
+
+impl TreeDb {
+    // Returns the loaded children of `node`, loading them from the DB if necessary.
+    fn load_edit_node<'a>(
+        &mut self,
+        node: &'a mut EditNode,
+        prefix: &str,
+    ) -> anyhow::Result<&'a mut BTreeMap<String, EditNode>> {
+        if let EditNode::Unloaded(id, node_type) = *node {
+            ensure!(node_type == NodeType::Tree, "{} is not a tree", prefix);
+            let Some(tree) = self.get_tree(&id)? else {
+                bail!("tree {} doesn't exist", id);
+            };
+            let children = tree
+                .children
+                .into_iter()
+                .map(|(name, (id, node_type))| (name, EditNode::Unloaded(id, node_type)))
+                .collect();
+            *node = EditNode::Loaded(children);
+        }
+        match node {
+            EditNode::Loaded(children) => Ok(children),
+            EditNode::Unloaded(..) => unreachable!(),
+        }
+    }
+
+    // Walks down to the parent of the last component of `path`, optionally creating missing trees
+    // along the way.
+    fn edit_parent<'a>(
+        &mut self,
+        root: &'a mut EditNode,
+        parents: &[&str],
+        create: bool,
+    ) -> anyhow::Result<&'a mut BTreeMap<String, EditNode>> {
+        let mut prefix = String::new();
+        let mut children = self.load_edit_node(root, "the root")?;
+        for &component in parents {
+            if !prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(component);
+            if create && !children.contains_key(component) {
+                children.insert(component.to_string(), EditNode::Loaded(BTreeMap::new()));
+            }
+            let Some(child) = children.get_mut(component) else {
+                bail!("{} not found", prefix);
+            };
+            children = self.load_edit_node(child, &prefix)?;
+        }
+        Ok(children)
+    }
+
+    // Merges the tree `id` into `node`, which must be a tree. Only the trees that both of them
+    // have at the same path get loaded, and every other subtree of `id` is reused as-is.
+    fn merge_edit_node(
+        &mut self,
+        node: &mut EditNode,
+        id: &blake3::Hash,
+        prefix: &str,
+    ) -> anyhow::Result<()> {
+        if let EditNode::Unloaded(node_id, _) = node
+            && node_id == id
+        {
+            return Ok(());
+        }
+        let Some(tree) = self.get_tree(id)? else {
+            bail!("tree {} doesn't exist", id);
+        };
+        let children = self.load_edit_node(node, prefix)?;
+        for (name, (child_id, node_type)) in tree.children {
+            match children.get_mut(&name) {
+                Some(child) if node_type == NodeType::Tree && child.is_tree() => {
+                    self.merge_edit_node(child, &child_id, &join_path(prefix, &name))?;
+                }
+                _ => {
+                    children.insert(name, EditNode::Unloaded(child_id, node_type));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies `edits` in order to the tree `root_id`, and returns the ID of the new root. Only
+    /// the trees along the edited paths are rebuilt and inserted. Every other subtree is reused.
+    /// Nothing is inserted if any edit fails.
+    pub fn edit_tree(
+        &mut self,
+        root_id: &blake3::Hash,
+        edits: &[TreeEdit],
+    ) -> anyhow::Result<blake3::Hash> {
+        let root = self.apply_edits(root_id, edits)?;
+
+        // Deferred transactions are vulnerable to BUSY errors if there are concurrent writers.
+        // See: https://fractaledmind.github.io/2024/04/15/sqlite-on-rails-the-how-and-why-of-optimal-performance/
+        let tx = self.conn.transaction_with_behavior(Immediate)?;
+        let (new_root_id, _) = insert_edit_node(&tx, &root)?;
+        tx.commit()?;
+        Ok(new_root_id)
+    }
+
+    // Applies `edits` to an in-memory copy of the tree `root_id`, without inserting anything. The
+    // caller inserts the result with insert_edit_node, which checks that every new child exists.
+    pub(crate) fn apply_edits(
+        &mut self,
+        root_id: &blake3::Hash,
+        edits: &[TreeEdit],
+    ) -> anyhow::Result<EditNode> {
+        let mut root = EditNode::Unloaded(*root_id, NodeType::Tree);
+        for edit in edits {
+            match edit {
+                TreeEdit::Set {
+                    path,
+                    id,
+                    node_type,
+                } => {
+                    let (parents, name) = path_components(path)?;
+                    let children = self.edit_parent(&mut root, &parents, true)?;
+                    children.insert(name.to_string(), EditNode::Unloaded(*id, *node_type));
+                }
+                TreeEdit::Remove { path } => {
+                    let (parents, name) = path_components(path)?;
+                    let children = self.edit_parent(&mut root, &parents, false)?;
+                    ensure!(children.remove(name).is_some(), "{} not found", path);
+                }
+                TreeEdit::Rename { from, to } => {
+                    let (from_parents, from_name) = path_components(from)?;
+                    let (to_parents, to_name) = path_components(to)?;
+                    // A tree can't be moved inside itself.
+                    let from_len = from_parents.len() + 1;
+                    ensure!(
+                        to_parents.len() < from_len
+                            || to_parents[..from_parents.len()] != from_parents[..]
+                            || to_parents[from_parents.len()] != from_name,
+                        "can't move {} into itself at {}",
+                        from,
+                        to,
+                    );
+                    let children = self.edit_parent(&mut root, &from_parents, false)?;
+                    let Some(node) = children.remove(from_name) else {
+                        bail!("{} not found", from);
+                    };
+                    let children = self.edit_parent(&mut root, &to_parents, true)?;
+                    children.insert(to_name.to_string(), node);
+                }
+                TreeEdit::Merge { path, id } => {
+                    if split_path(path).next().is_none() {
+                        self.merge_edit_node(&mut root, id, "")?;
+                        continue;
+                    }
+                    let (parents, name) = path_components(path)?;
+                    let children = self.edit_parent(&mut root, &parents, true)?;
+                    match children.get_mut(name) {
+                        Some(child) if child.is_tree() => {
+                            let prefix = split_path(path).collect::<Vec<_>>().join("/");
+                            self.merge_edit_node(child, id, &prefix)?;
+                        }
+                        _ => {
+                            children
+                                .insert(name.to_string(), EditNode::Unloaded(*id, NodeType::Tree));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(root)
+    }
+
+    /// Returns the ID and type of the entry at `path` under the tree `root_id`, where `path` is
+    /// separated by `/`. An empty path refers to the root itself. Returns an error naming the
+    /// first missing component if there's no such entry.
+    pub fn lookup_path(
+        &mut self,
+        root_id: &blake3::Hash,
+        path: &str,
+    ) -> anyhow::Result<(blake3::Hash, NodeType)> {
+        self.resolve_path(root_id, path)?
+            .map_err(anyhow::Error::msg)
+    }
+
+    // Like lookup_path, but returns None if there's no entry at `path`, and only returns errors
+    // for a missing root or a database failure.
+    pub(crate) fn find_path(
+        &mut self,
+        root_id: &blake3::Hash,
+        path: &str,
+    ) -> anyhow::Result<Option<(blake3::Hash, NodeType)>> {
+        Ok(self.resolve_path(root_id, path)?.ok())
+    }
+
+    // The inner result is the entry, or a message saying why there isn't one.
+    fn resolve_path(
+        &mut self,
+        root_id: &blake3::Hash,
+        path: &str,
+    ) -> anyhow::Result<Result<(blake3::Hash, NodeType), String>> {
+        ensure!(
+            tree_exists(&self.conn, root_id)?,
+            "tree {} does not exist",
+            root_id,
+        );
+        let mut query = self.conn.prepare(
+            "SELECT child_id, node_type, executable FROM trees WHERE tree_id = ? AND child_name = ?",
+        )?;
+        let mut current = (*root_id, NodeType::Tree);
+        let mut prefix = String::new();
+        for component in split_path(path) {
+            if current.1 != NodeType::Tree {
+                return Ok(Err(format!("{} is not a tree", prefix)));
+            }
+            let row: Option<([u8; 32], u8, bool)> = query
+                .query_row((current.0.as_bytes(), component), |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                })
+                .optional()?;
+            let Some((child_id, node_type, executable)) = row else {
+                let parent = if prefix.is_empty() {
+                    "the root"
+                } else {
+                    &prefix
+                };
+                let message = format!("{} not found: no entry {:?} in {}", path, component, parent);
+                return Ok(Err(message));
+            };
+            current = (child_id.into(), parse_node_type(node_type, executable)?);
+            if !prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(component);
+        }
+        Ok(Ok(current))
+    }
+}
+
+// Inserts the loaded trees under `node` bottom-up. The caller is responsible for the transaction.
+pub(crate) fn insert_edit_node(
+    conn: &rusqlite::Connection,
+    node: &EditNode,
+) -> anyhow::Result<(blake3::Hash, NodeType)> {
+    match node {
+        EditNode::Unloaded(id, node_type) => Ok((*id, *node_type)),
+        EditNode::Loaded(children) => {
+            let mut tree = Tree::new();
+            for (name, child) in children {
+                let (id, node_type) = insert_edit_node(conn, child)?;
+                tree.add_child(name.clone(), &id, node_type);
+            }
+            Ok((insert_tree_rows(conn, &tree)?, NodeType::Tree))
+        }
+    }
+}
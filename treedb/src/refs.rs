@@ -0,0 +1,92 @@
+use crate::{TreeDb, tree_exists};
+use anyhow::ensure;
+use rusqlite::{OptionalExtension, TransactionBehavior::Immediate};
+
+fn check_tree_exists(conn: &rusqlite::Connection, tree_id: &blake3::Hash) -> anyhow::Result<()> {
+    ensure!(
+        tree_exists(conn, tree_id)?,
+        "tree {} does not exist",
+        tree_id
+    );
+    Ok(())
+}
+
+fn get_ref(conn: &rusqlite::Connection, name: &str) -> anyhow::Result<Option<blake3::Hash>> {
+    let tree_id: Option<[u8; 32]> = conn
+        .query_row("SELECT tree_id FROM refs WHERE name = ?", (name,), |row| {
+            row.get(0)
+        })
+        .optional()?;
+    Ok(tree_id.map(Into::into))
+}
+
+// Refs are names like `main/latest-build` that point to trees. Trees that refs point to are
+// roots for gc().
+impl TreeDb {
+    /// Points `name` at `tree_id`, which must exist, replacing any previous value.
+    pub fn set_ref(&mut self, name: &str, tree_id: &blake3::Hash) -> anyhow::Result<()> {
+        let tx = self.conn.transaction_with_behavior(Immediate)?;
+        check_tree_exists(&tx, tree_id)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO refs (name, tree_id) VALUES (?, ?)",
+            (name, tree_id.as_bytes()),
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Returns the tree that `name` points to, or `None` if there's no such ref.
+    pub fn get_ref(&mut self, name: &str) -> anyhow::Result<Option<blake3::Hash>> {
+        get_ref(&self.conn, name)
+    }
+
+    /// Deletes `name`, returning whether it existed.
+    pub fn delete_ref(&mut self, name: &str) -> anyhow::Result<bool> {
+        let deleted = self
+            .conn
+            .execute("DELETE FROM refs WHERE name = ?", (name,))?;
+        Ok(deleted > 0)
+    }
+
+    /// Returns all refs, sorted by name.
+    pub fn list_refs(&mut self) -> anyhow::Result<Vec<(String, blake3::Hash)>> {
+        let mut query = self
+            .conn
+            .prepare("SELECT name, tree_id FROM refs ORDER BY name")?;
+        let rows = query.query_map((), |row| {
+            let name: String = row.get(0)?;
+            let tree_id: [u8; 32] = row.get(1)?;
+            Ok((name, tree_id.into()))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Atomically points `name` at `new` if it currently points at `expected`, and returns whether
+    /// it did. `None` for `expected` means the ref must not exist yet, and `None` for `new` deletes
+    /// it.
+    pub fn compare_and_swap_ref(
+        &mut self,
+        name: &str,
+        expected: Option<&blake3::Hash>,
+        new: Option<&blake3::Hash>,
+    ) -> anyhow::Result<bool> {
+        let tx = self.conn.transaction_with_behavior(Immediate)?;
+        if get_ref(&tx, name)?.as_ref() != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(tree_id) => {
+                check_tree_exists(&tx, tree_id)?;
+                tx.execute(
+                    "INSERT OR REPLACE INTO refs (name, tree_id) VALUES (?, ?)",
+                    (name, tree_id.as_bytes()),
+                )?;
+            }
+            None => {
+                tx.execute("DELETE FROM refs WHERE name = ?", (name,))?;
+            }
+        }
+        tx.commit()?;
+        Ok(true)
+    }
+}
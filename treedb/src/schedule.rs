@@ -0,0 +1,299 @@
+use crate::exec::Sandbox;
+use crate::{Action, ActionResult, Tree, TreeDb, TreeEdit};
+use anyhow::{Context, bail, ensure};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::process::Output;
+use std::sync::mpsc;
+use std::{io, thread};
+
+/// Where one of a [`Target`]'s input trees comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TargetInput {
+    /// A tree that's already in the store, such as a source directory.
+    Tree(blake3::Hash),
+    /// The output tree of another target, by name.
+    Target(String),
+}
+
+/// A node of a build graph: a command, and the trees it needs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Target {
+    pub name: String,
+    /// The input trees, and the `/`-separated paths where they go in the action's input tree.
+    /// They're placed in order, so an input can go inside an earlier one. An input that lands on
+    /// an existing tree is merged into it as with [`TreeEdit::Merge`], so several inputs can share
+    /// a directory, including the root at an empty path.
+    pub inputs: Vec<(String, TargetInput)>,
+    /// The command line, environment, and output paths, as in [`Action`].
+    pub argv: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    pub output_paths: Vec<String>,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct BuildOptions {
+    /// The maximum number of commands to run at once.
+    pub jobs: usize,
+    /// Whether to keep building targets that don't depend on a failed one. Otherwise no new
+    /// commands start after the first failure, though the ones already running finish.
+    pub keep_going: bool,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        Self {
+            jobs: thread::available_parallelism().map_or(1, |n| n.get()),
+            keep_going: false,
+        }
+    }
+}
+
+/// What happened to a target during [`TreeDb::build`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TargetOutcome {
+    /// The action already had a result in the action cache, so it didn't run.
+    Cached(ActionResult),
+    /// The action ran and succeeded, and its result is now cached.
+    Built(ActionResult),
+    /// The action ran, but it exited with a nonzero status or didn't produce all of its output
+    /// paths. Failures aren't cached.
+    Failed(ActionResult),
+    /// The target didn't run, because a dependency failed or the build stopped early.
+    Skipped,
+}
+
+impl TargetOutcome {
+    /// The result of the target's action, if it has one.
+    pub fn result(&self) -> Option<&ActionResult> {
+        match self {
+            TargetOutcome::Cached(result)
+            | TargetOutcome::Built(result)
+            | TargetOutcome::Failed(result) => Some(result),
+            TargetOutcome::Skipped => None,
+        }
+    }
+}
+
+// Returns the indexes of each target's dependencies, after checking that the graph is well-formed.
+fn resolve_dependencies(targets: &[Target]) -> anyhow::Result<Vec<Vec<usize>>> {
+    let mut indexes = HashMap::new();
+    for (index, target) in targets.iter().enumerate() {
+        ensure!(
+            indexes.insert(target.name.as_str(), index).is_none(),
+            "duplicate target {:?}",
+            target.name,
+        );
+    }
+    let mut dependencies = Vec::new();
+    for target in targets {
+        let mut target_dependencies = Vec::new();
+        for (_, input) in &target.inputs {
+            if let TargetInput::Target(name) = input {
+                let Some(&index) = indexes.get(name.as_str()) else {
+                    bail!(
+                        "target {:?} depends on unknown target {:?}",
+                        target.name,
+                        name
+                    );
+                };
+                target_dependencies.push(index);
+            }
+        }
+        dependencies.push(target_dependencies);
+    }
+
+    // Check for cycles by repeatedly removing targets whose dependencies have all been removed.
+    let mut removed = vec![false; targets.len()];
+    loop {
+        let mut progress = false;
+        for index in 0..targets.len() {
+            if !removed[index] && dependencies[index].iter().all(|&i| removed[i]) {
+                removed[index] = true;
+                progress = true;
+            }
+        }
+        if !progress {
+            break;
+        }
+    }
+    if let Some(index) = removed.iter().position(|&removed| !removed) {
+        bail!(
+            "build graph has a cycle involving target {:?}",
+            targets[index].name,
+        );
+    }
+    Ok(dependencies)
+}
+
+// The state of a build in progress. Commands run on their own threads, but everything that touches
+// the database happens on the calling thread.
+struct Scheduler<'a> {
+    targets: &'a [Target],
+    options: BuildOptions,
+    // Indexes of each target's dependencies, in the order of its inputs.
+    dependencies: Vec<Vec<usize>>,
+    dependents: Vec<Vec<usize>>,
+    // The number of each target's dependencies that haven't succeeded yet.
+    waiting: Vec<usize>,
+    ready: VecDeque<usize>,
+    outcomes: Vec<Option<TargetOutcome>>,
+    running: HashMap<usize, (Action, Sandbox)>,
+    sender: mpsc::Sender<(usize, io::Result<Output>)>,
+    receiver: mpsc::Receiver<(usize, io::Result<Output>)>,
+    stopped: bool,
+}
+
+impl Scheduler<'_> {
+    fn run(&mut self, db: &mut TreeDb) -> anyhow::Result<()> {
+        loop {
+            while !self.stopped && self.running.len() < self.options.jobs {
+                let Some(index) = self.ready.pop_front() else {
+                    break;
+                };
+                self.start(db, index)
+                    .with_context(|| format!("failed to start target {:?}", self.name(index)))?;
+            }
+            if self.running.is_empty() {
+                return Ok(());
+            }
+            let (index, output) = self.receiver.recv().expect("sender");
+            let (action, sandbox) = self.running.remove(&index).expect("running target");
+            let output =
+                output.with_context(|| format!("failed to run target {:?}", self.name(index)))?;
+            let result = db.finish_sandbox(sandbox, output)?;
+            let outcome = if result.exit_code == 0 && has_outputs(db, &action, &result)? {
+                db.insert_action_result(&action.key(), &result)?;
+                TargetOutcome::Built(result)
+            } else {
+                TargetOutcome::Failed(result)
+            };
+            self.complete(index, outcome);
+        }
+    }
+
+    fn name(&self, index: usize) -> &str {
+        &self.targets[index].name
+    }
+
+    // Finishes the target right away if its result is cached, and otherwise starts its command.
+    fn start(&mut self, db: &mut TreeDb, index: usize) -> anyhow::Result<()> {
+        let target = &self.targets[index];
+        let mut dependencies = self.dependencies[index].iter();
+        let mut edits = Vec::new();
+        for (path, input) in &target.inputs {
+            let id = match input {
+                TargetInput::Tree(id) => *id,
+                TargetInput::Target(_) => {
+                    let dependency = *dependencies.next().expect("dependency");
+                    let outcome = self.outcomes[dependency].as_ref().expect("finished");
+                    outcome.result().expect("succeeded").output_tree
+                }
+            };
+            edits.push(TreeEdit::Merge {
+                path: path.clone(),
+                id,
+            });
+        }
+        let empty_id = db.insert_tree(&Tree::new())?;
+        let action = Action {
+            argv: target.argv.clone(),
+            env: target.env.clone(),
+            input_tree: db.edit_tree(&empty_id, &edits)?,
+            output_paths: target.output_paths.clone(),
+        };
+        if let Some(result) = db.get_action_result(&action.key())? {
+            self.complete(index, TargetOutcome::Cached(result));
+            return Ok(());
+        }
+        let sandbox = db.prepare_sandbox(&action)?;
+        let mut command = sandbox.command(&action);
+        let sender = self.sender.clone();
+        thread::spawn(move || {
+            // The receiver only goes away once nothing is running.
+            _ = sender.send((index, command.output()));
+        });
+        self.running.insert(index, (action, sandbox));
+        Ok(())
+    }
+
+    fn complete(&mut self, index: usize, outcome: TargetOutcome) {
+        if let TargetOutcome::Failed(_) = outcome {
+            self.stopped |= !self.options.keep_going;
+        } else {
+            for &dependent in &self.dependents[index] {
+                self.waiting[dependent] -= 1;
+                if self.waiting[dependent] == 0 {
+                    self.ready.push_back(dependent);
+                }
+            }
+        }
+        self.outcomes[index] = Some(outcome);
+    }
+}
+
+fn has_outputs(db: &mut TreeDb, action: &Action, result: &ActionResult) -> anyhow::Result<bool> {
+    for path in &action.output_paths {
+        if db.find_path(&result.output_tree, path)?.is_none() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+impl TreeDb {
+    /// Builds a graph of targets with [`run_action`](TreeDb::run_action), and returns what
+    /// happened to each one, by name.
+    ///
+    /// Targets run once all the targets they depend on have succeeded, up to `options.jobs` at a
+    /// time, and otherwise in the order they're listed. A target whose action is already in the
+    /// action cache doesn't run at all, and successful results are added to the cache. Returns an
+    /// error without running anything if a target name is duplicated, an input names an unknown
+    /// target, or the graph has a cycle. Database errors and commands that can't be started also
+    /// stop the build with an error, after waiting for the commands that are still running.
+    pub fn build(
+        &mut self,
+        targets: &[Target],
+        options: &BuildOptions,
+    ) -> anyhow::Result<BTreeMap<String, TargetOutcome>> {
+        ensure!(options.jobs > 0, "jobs must be at least 1");
+        let dependencies = resolve_dependencies(targets)?;
+        let mut dependents = vec![Vec::new(); targets.len()];
+        for (index, target_dependencies) in dependencies.iter().enumerate() {
+            for &dependency in target_dependencies {
+                dependents[dependency].push(index);
+            }
+        }
+        let (sender, receiver) = mpsc::channel();
+        let mut scheduler = Scheduler {
+            targets,
+            options: *options,
+            waiting: dependencies.iter().map(Vec::len).collect(),
+            ready: (0..targets.len())
+                .filter(|&index| dependencies[index].is_empty())
+                .collect(),
+            dependencies,
+            dependents,
+            outcomes: vec![None; targets.len()],
+            running: HashMap::new(),
+            sender,
+            receiver,
+            stopped: false,
+        };
+        let result = scheduler.run(self);
+        // Don't delete sandboxes out from under commands that are still running.
+        for _ in 0..scheduler.running.len() {
+            _ = scheduler.receiver.recv();
+        }
+        result?;
+        Ok(targets
+            .iter()
+            .zip(scheduler.outcomes)
+            .map(|(target, outcome)| {
+                (
+                    target.name.clone(),
+                    outcome.unwrap_or(TargetOutcome::Skipped),
+                )
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,65 @@
+use crate::TreeDb;
+use crate::compress::Codec;
+use crate::gc::is_blob_file_name;
+use std::fs;
+
+/// Object counts and sizes, returned by [`TreeDb::stats`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    pub blobs: u64,
+    /// Blobs stored in the blobs dir or as chunks, rather than in the blobs table.
+    pub large_blobs: u64,
+    pub compressed_blobs: u64,
+    pub chunked_blobs: u64,
+    /// Distinct chunks, which chunked blobs can share.
+    pub chunks: u64,
+    /// Trees with at least one child. The empty tree isn't stored.
+    pub trees: u64,
+    pub refs: u64,
+    pub action_results: u64,
+    /// The space that blob data takes up, after compression and chunk sharing, in the blobs table,
+    /// the chunks table, and the blobs dir. Outboards and SQLite overhead aren't counted.
+    pub stored_bytes: u64,
+}
+
+impl TreeDb {
+    /// Counts the objects in the store. This reads every row and lists the blobs dir, so it's
+    /// meant for inspection rather than for hot paths.
+    pub fn stats(&self) -> anyhow::Result<StoreStats> {
+        let count = |sql: &str| -> anyhow::Result<u64> {
+            Ok(self.conn.query_row(sql, (), |row| row.get(0))?)
+        };
+        let mut stats = StoreStats {
+            blobs: count("SELECT COUNT(*) FROM blobs")?,
+            large_blobs: count("SELECT COUNT(*) FROM blobs WHERE data IS NULL")?,
+            compressed_blobs: count(&format!(
+                "SELECT COUNT(*) FROM blobs WHERE codec = {}",
+                Codec::Zstd.to_column(),
+            ))?,
+            chunked_blobs: count(&format!(
+                "SELECT COUNT(*) FROM blobs WHERE codec = {}",
+                Codec::Chunked.to_column(),
+            ))?,
+            chunks: count("SELECT COUNT(*) FROM chunks")?,
+            trees: count("SELECT COUNT(DISTINCT tree_id) FROM trees")?,
+            refs: count("SELECT COUNT(*) FROM refs")?,
+            action_results: count("SELECT COUNT(*) FROM actions")?,
+            stored_bytes: count(
+                "SELECT (SELECT IFNULL(SUM(LENGTH(data)), 0) FROM blobs)
+                      + (SELECT IFNULL(SUM(LENGTH(data)), 0) FROM chunks)",
+            )?,
+        };
+        // Tempfiles and gc's leftovers aren't blob data.
+        for entry in fs::read_dir(&self.blobs_dir)? {
+            let entry = entry?;
+            if !is_blob_file_name(&entry.file_name().to_string_lossy()) {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            if metadata.is_file() {
+                stats.stored_bytes += metadata.len();
+            }
+        }
+        Ok(stats)
+    }
+}
@@ -1,5 +1,6 @@
 use super::*;
 use std::fs;
+use std::time::Duration;
 use tempfile::NamedTempFile;
 
 fn big_blob_tempfile() -> anyhow::Result<NamedTempFile> {
@@ -25,7 +26,7 @@ fn test_basic() -> anyhow::Result<()> {
     let foo_id = conn.insert_blob(b"foo")?;
     let big_file = big_blob_tempfile()?;
     let big_bytes = fs::read(big_file.path())?;
-    let big_id = conn.insert_file(&big_file.path())?;
+    let big_id = conn.insert_file(big_file.path())?;
     let mut c_tree = Tree::new();
     c_tree.add_child("d", &big_id, NodeType::Blob { executable: false });
     let c_id = conn.insert_tree(&c_tree)?;
@@ -105,3 +106,2003 @@ fn test_children_must_exist() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_insert_dir() -> anyhow::Result<()> {
+    // Test data:
+    // - a: b"foo"
+    // - b: b"foo" (executable)
+    // - c/d: <LARGE_BLOB_THRESHOLD random bytes>
+    // - c/e/f: <the same large bytes>
+
+    let dir = tempfile::tempdir()?;
+    let db_path = dir.path().join("db");
+    let src = dir.path().join("src");
+    fs::create_dir_all(src.join("c/e"))?;
+    fs::write(src.join("a"), b"foo")?;
+    fs::write(src.join("b"), b"foo")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(src.join("b"), fs::Permissions::from_mode(0o755))?;
+    }
+    let big_file = big_blob_tempfile()?;
+    fs::copy(big_file.path(), src.join("c/d"))?;
+    fs::copy(big_file.path(), src.join("c/e/f"))?;
+    let mut conn = TreeDb::open(db_path)?;
+    let root_id = conn.insert_dir(&src)?;
+
+    // Build the same tree by hand and check that the IDs match.
+    let foo_id = blake3::hash(b"foo");
+    let big_id = blake3::hash(&fs::read(big_file.path())?);
+    let mut e_tree = Tree::new();
+    e_tree.add_child("f", &big_id, NodeType::Blob { executable: false });
+    let mut c_tree = Tree::new();
+    c_tree.add_child("d", &big_id, NodeType::Blob { executable: false });
+    c_tree.add_child("e", &e_tree.id(), NodeType::Tree);
+    let mut root = Tree::new();
+    root.add_child("a", &foo_id, NodeType::Blob { executable: false });
+    root.add_child(
+        "b",
+        &foo_id,
+        NodeType::Blob {
+            executable: cfg!(unix),
+        },
+    );
+    root.add_child("c", &c_tree.id(), NodeType::Tree);
+    assert_eq!(root_id, root.id());
+    assert_eq!(conn.get_tree(&root_id)?.unwrap(), root);
+    assert_eq!(conn.get_tree(&c_tree.id())?.unwrap(), c_tree);
+    assert_eq!(conn.get_blob(&foo_id)?, b"foo");
+    assert_eq!(conn.get_blob(&big_id)?, fs::read(big_file.path())?);
+
+    // Snapshotting again is a no-op, and the source files are left writable.
+    assert_eq!(conn.insert_dir(&src)?, root_id);
+    assert!(!fs::metadata(src.join("c/d"))?.permissions().readonly());
+
+    Ok(())
+}
+
+#[test]
+fn test_checkout_tree() -> anyhow::Result<()> {
+    // Test data:
+    // - a: b"foo" (executable)
+    // - b/c: <LARGE_BLOB_THRESHOLD random bytes>
+    // - b/d/e: b"bar"
+
+    let dir = tempfile::tempdir()?;
+    let src = dir.path().join("src");
+    fs::create_dir_all(src.join("b/d"))?;
+    fs::write(src.join("a"), b"foo")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(src.join("a"), fs::Permissions::from_mode(0o755))?;
+    }
+    let big_file = big_blob_tempfile()?;
+    fs::copy(big_file.path(), src.join("b/c"))?;
+    fs::write(src.join("b/d/e"), b"bar")?;
+    let mut conn = TreeDb::open(dir.path().join("db"))?;
+    let root_id = conn.insert_dir(&src)?;
+
+    let dest = dir.path().join("dest");
+    conn.checkout_tree(&root_id, &dest)?;
+    assert_eq!(fs::read(dest.join("a"))?, b"foo");
+    assert_eq!(fs::read(dest.join("b/c"))?, fs::read(big_file.path())?);
+    assert_eq!(fs::read(dest.join("b/d/e"))?, b"bar");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = |path: &str| -> anyhow::Result<u32> {
+            Ok(fs::metadata(dest.join(path))?.permissions().mode() & 0o777)
+        };
+        assert_eq!(mode("a")?, 0o755);
+        assert_eq!(mode("b/c")?, 0o644);
+        assert_eq!(mode("b/d/e")?, 0o644);
+    }
+    // Checking out the result again gives the same tree.
+    assert_eq!(conn.insert_dir(&dest)?, root_id);
+
+    // The destination must be empty.
+    conn.checkout_tree(&root_id, &dest).unwrap_err();
+
+    Ok(())
+}
+
+#[test]
+fn test_update_checkout() -> anyhow::Result<()> {
+    // Tree A:
+    // - a: b"foo"
+    // - b/c: b"bar"
+    // - d/e: b"baz"
+    // - f: b"qux"
+    // Tree B:
+    // - a: b"foo" (executable)
+    // - b/c: b"bar"
+    // - d: b"baz"
+    // - g/h: b"new"
+
+    let dir = tempfile::tempdir()?;
+    let mut conn = TreeDb::open(dir.path().join("db"))?;
+    let src_a = dir.path().join("a");
+    fs::create_dir_all(src_a.join("b"))?;
+    fs::create_dir_all(src_a.join("d"))?;
+    fs::write(src_a.join("a"), b"foo")?;
+    fs::write(src_a.join("b/c"), b"bar")?;
+    fs::write(src_a.join("d/e"), b"baz")?;
+    fs::write(src_a.join("f"), b"qux")?;
+    let a_id = conn.insert_dir(&src_a)?;
+    let src_b = dir.path().join("b");
+    fs::create_dir_all(src_b.join("b"))?;
+    fs::create_dir_all(src_b.join("g"))?;
+    fs::write(src_b.join("a"), b"foo")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(src_b.join("a"), fs::Permissions::from_mode(0o755))?;
+    }
+    fs::write(src_b.join("b/c"), b"bar")?;
+    fs::write(src_b.join("d"), b"baz")?;
+    fs::write(src_b.join("g/h"), b"new")?;
+    let b_id = conn.insert_dir(&src_b)?;
+
+    // A -> B -> A round trips.
+    let work = dir.path().join("work");
+    conn.checkout_tree(&a_id, &work)?;
+    conn.update_checkout(&a_id, &b_id, &work, false)?;
+    assert_eq!(conn.insert_dir(&work)?, b_id);
+    conn.update_checkout(&b_id, &a_id, &work, false)?;
+    assert_eq!(conn.insert_dir(&work)?, a_id);
+
+    // A local modification blocks the update, and nothing is changed.
+    fs::write(work.join("f"), b"modified")?;
+    conn.update_checkout(&a_id, &b_id, &work, false)
+        .unwrap_err();
+    assert!(work.join("d/e").exists());
+    assert_eq!(fs::read(work.join("f"))?, b"modified");
+
+    // So does an untracked file in a directory that would be deleted, or in the way of a new one.
+    fs::write(work.join("f"), b"qux")?;
+    fs::write(work.join("d/untracked"), b"")?;
+    conn.update_checkout(&a_id, &b_id, &work, false)
+        .unwrap_err();
+    fs::remove_file(work.join("d/untracked"))?;
+    fs::write(work.join("g"), b"")?;
+    conn.update_checkout(&a_id, &b_id, &work, false)
+        .unwrap_err();
+
+    // Unless forced.
+    fs::write(work.join("f"), b"modified")?;
+    conn.update_checkout(&a_id, &b_id, &work, true)?;
+    assert_eq!(conn.insert_dir(&work)?, b_id);
+
+    Ok(())
+}
+
+#[test]
+fn test_diff_trees() -> anyhow::Result<()> {
+    // Old tree:
+    // - a: b"foo"
+    // - b/c: b"bar"
+    // - d/e: b"baz"
+    // - f: b"qux"
+    // - same/x: b"x"
+    // New tree:
+    // - a: b"foo" (executable)
+    // - b/c: b"changed"
+    // - d: b"baz"
+    // - g/h: b"new"
+    // - same/x: b"x"
+
+    let dir = tempfile::tempdir()?;
+    let mut conn = TreeDb::open(dir.path().join("db"))?;
+    let blob = NodeType::Blob { executable: false };
+    let foo = conn.insert_blob(b"foo")?;
+    let bar = conn.insert_blob(b"bar")?;
+    let baz = conn.insert_blob(b"baz")?;
+    let qux = conn.insert_blob(b"qux")?;
+    let changed = conn.insert_blob(b"changed")?;
+    let new = conn.insert_blob(b"new")?;
+    let x = conn.insert_blob(b"x")?;
+    let tree_of = |conn: &mut TreeDb, name: &str, id: &blake3::Hash| {
+        let mut tree = Tree::new();
+        tree.add_child(name, id, blob);
+        conn.insert_tree(&tree)
+    };
+    let old_b = tree_of(&mut conn, "c", &bar)?;
+    let new_b = tree_of(&mut conn, "c", &changed)?;
+    let old_d = tree_of(&mut conn, "e", &baz)?;
+    let new_g = tree_of(&mut conn, "h", &new)?;
+    let same = tree_of(&mut conn, "x", &x)?;
+    let mut old = Tree::new();
+    old.add_child("a", &foo, blob);
+    old.add_child("b", &old_b, NodeType::Tree);
+    old.add_child("d", &old_d, NodeType::Tree);
+    old.add_child("f", &qux, blob);
+    old.add_child("same", &same, NodeType::Tree);
+    let old_id = conn.insert_tree(&old)?;
+    let mut new_tree = Tree::new();
+    new_tree.add_child("a", &foo, NodeType::Blob { executable: true });
+    new_tree.add_child("b", &new_b, NodeType::Tree);
+    new_tree.add_child("d", &baz, blob);
+    new_tree.add_child("g", &new_g, NodeType::Tree);
+    new_tree.add_child("same", &same, NodeType::Tree);
+    let new_id = conn.insert_tree(&new_tree)?;
+
+    let expected = vec![
+        DiffEntry::Modified {
+            path: "a".into(),
+            old_id: foo,
+            old_node_type: blob,
+            new_id: foo,
+            new_node_type: NodeType::Blob { executable: true },
+        },
+        DiffEntry::Modified {
+            path: "b/c".into(),
+            old_id: bar,
+            old_node_type: blob,
+            new_id: changed,
+            new_node_type: blob,
+        },
+        DiffEntry::TypeChanged {
+            path: "d".into(),
+            old_id: old_d,
+            old_node_type: NodeType::Tree,
+            new_id: baz,
+            new_node_type: blob,
+        },
+        DiffEntry::Removed {
+            path: "f".into(),
+            id: qux,
+            node_type: blob,
+        },
+        DiffEntry::Added {
+            path: "g".into(),
+            id: new_g,
+            node_type: NodeType::Tree,
+        },
+    ];
+    assert_eq!(conn.diff_trees(&old_id, &new_id)?, expected);
+    assert_eq!(conn.diff_trees(&old_id, &old_id)?, vec![]);
+
+    Ok(())
+}
+
+#[test]
+fn test_gc() -> anyhow::Result<()> {
+    // Kept:
+    // - a: b"foo"
+    // - b/c: <LARGE_BLOB_THRESHOLD random bytes>
+    // Garbage:
+    // - a: b"bar"
+    // - b/c: <different random bytes>
+
+    let dir = tempfile::tempdir()?;
+    let db_path = dir.path().join("db");
+    let mut conn = TreeDb::open(&db_path)?;
+    conn.set_gc_grace_period(Duration::ZERO);
+    let blob = NodeType::Blob { executable: false };
+    let insert_root = |conn: &mut TreeDb, small: &[u8]| -> anyhow::Result<_> {
+        let small_id = conn.insert_blob(small)?;
+        let big_id = conn.insert_file(big_blob_tempfile()?.path())?;
+        let mut b_tree = Tree::new();
+        b_tree.add_child("c", &big_id, blob);
+        let b_id = conn.insert_tree(&b_tree)?;
+        let mut root = Tree::new();
+        root.add_child("a", &small_id, blob);
+        root.add_child("b", &b_id, NodeType::Tree);
+        Ok((conn.insert_tree(&root)?, small_id, big_id, b_id))
+    };
+    let (kept_root, kept_small, kept_big, kept_b) = insert_root(&mut conn, b"foo")?;
+    let (garbage_root, garbage_small, garbage_big, garbage_b) = insert_root(&mut conn, b"bar")?;
+    // A leftover file from an insert that failed before committing.
+    let leftover = conn.blob_path(&blake3::hash(b"leftover"));
+    fs::write(&leftover, b"leftover")?;
+
+    let stats = conn.gc(&[kept_root])?;
+    assert_eq!(
+        stats,
+        GcStats {
+            trees: 2,
+            blobs: 2,
+            chunks: 0,
+            files: 2,
+        },
+    );
+    assert!(conn.get_tree(&kept_root)?.is_some());
+    assert!(conn.get_tree(&kept_b)?.is_some());
+    assert!(conn.contains_blob(kept_small)?);
+    assert!(conn.contains_blob(kept_big)?);
+    assert!(conn.get_tree(&garbage_root)?.is_none());
+    assert!(conn.get_tree(&garbage_b)?.is_none());
+    assert!(!conn.contains_blob(garbage_small)?);
+    assert!(!conn.contains_blob(garbage_big)?);
+    assert!(conn.blob_path(&kept_big).exists());
+    assert!(!conn.blob_path(&garbage_big).exists());
+    assert!(!leftover.exists());
+
+    // Nothing left to collect, and unknown roots are an error.
+    assert_eq!(conn.gc(&[kept_root])?, GcStats::default());
+    conn.gc(&[garbage_root]).unwrap_err();
+
+    // Within the grace period, a writer's blobs survive a GC that runs before the writer inserts
+    // the tree that links them.
+    conn.set_gc_grace_period(Duration::from_secs(60 * 60));
+    let new_small = conn.insert_blob(b"new")?;
+    let new_big = conn.insert_file(big_blob_tempfile()?.path())?;
+    assert_eq!(conn.gc(&[kept_root])?, GcStats::default());
+    let mut tree = Tree::new();
+    tree.add_child("small", &new_small, blob);
+    tree.add_child("big", &new_big, blob);
+    tree.add_child("old", &kept_small, blob);
+    let tree_id = conn.insert_tree(&tree)?;
+    assert_eq!(conn.gc(&[kept_root])?, GcStats::default());
+    assert_eq!(conn.get_tree(&tree_id)?, Some(tree));
+
+    // After the grace period, they're garbage again.
+    conn.set_gc_grace_period(Duration::ZERO);
+    let stats = conn.gc(&[kept_root])?;
+    assert_eq!(
+        stats,
+        GcStats {
+            trees: 1,
+            blobs: 2,
+            chunks: 0,
+            files: 1,
+        },
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_refs() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut conn = TreeDb::open(dir.path().join("db"))?;
+    let blob = NodeType::Blob { executable: false };
+    let mut tree1 = Tree::new();
+    tree1.add_child("a", &conn.insert_blob(b"foo")?, blob);
+    let id1 = conn.insert_tree(&tree1)?;
+    let mut tree2 = Tree::new();
+    tree2.add_child("a", &conn.insert_blob(b"bar")?, blob);
+    let id2 = conn.insert_tree(&tree2)?;
+
+    assert_eq!(conn.get_ref("main/latest-build")?, None);
+    conn.set_ref("main/latest-build", &id1)?;
+    conn.set_ref("other", &id2)?;
+    assert_eq!(conn.get_ref("main/latest-build")?, Some(id1));
+    assert_eq!(
+        conn.list_refs()?,
+        vec![("main/latest-build".into(), id1), ("other".into(), id2)],
+    );
+
+    // Refs can only point to trees that exist.
+    conn.set_ref("bad", &blake3::hash(b"nope")).unwrap_err();
+
+    // Compare-and-swap.
+    assert!(!conn.compare_and_swap_ref("main/latest-build", Some(&id2), Some(&id2))?);
+    assert!(!conn.compare_and_swap_ref("main/latest-build", None, Some(&id2))?);
+    assert!(conn.compare_and_swap_ref("main/latest-build", Some(&id1), Some(&id2))?);
+    assert_eq!(conn.get_ref("main/latest-build")?, Some(id2));
+    assert!(conn.compare_and_swap_ref("new", None, Some(&id1))?);
+    assert!(conn.compare_and_swap_ref("new", Some(&id1), None)?);
+    assert_eq!(conn.get_ref("new")?, None);
+
+    // Refs are GC roots.
+    conn.set_gc_grace_period(Duration::ZERO);
+    assert!(conn.delete_ref("other")?);
+    assert!(!conn.delete_ref("other")?);
+    let stats = conn.gc(&[])?;
+    assert_eq!(stats.trees, 1);
+    assert!(conn.get_tree(&id2)?.is_some());
+    assert!(conn.get_tree(&id1)?.is_none());
+
+    Ok(())
+}
+
+#[test]
+fn test_open_blob() -> anyhow::Result<()> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let dir = tempfile::tempdir()?;
+    let mut conn = TreeDb::open(dir.path().join("db"))?;
+    let small_id = conn.insert_blob(b"hello world")?;
+    let big_file = big_blob_tempfile()?;
+    let big_bytes = fs::read(big_file.path())?;
+    let big_id = conn.insert_file(big_file.path())?;
+
+    for (id, bytes) in [(small_id, &b"hello world"[..]), (big_id, &big_bytes[..])] {
+        let mut reader = conn.open_blob(&id)?;
+        assert_eq!(reader.len(), bytes.len() as u64);
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents)?;
+        assert_eq!(contents, bytes);
+        reader.seek(SeekFrom::Start(6))?;
+        let mut buf = [0; 5];
+        reader.read_exact(&mut buf)?;
+        assert_eq!(buf, bytes[6..11]);
+    }
+    conn.open_blob(&blake3::hash(b"nope")).unwrap_err();
+
+    Ok(())
+}
+
+#[test]
+fn test_blob_writer() -> anyhow::Result<()> {
+    use std::io::Write;
+
+    let dir = tempfile::tempdir()?;
+    let mut conn = TreeDb::open(dir.path().join("db"))?;
+    let big_file = big_blob_tempfile()?;
+    let mut big_bytes = fs::read(big_file.path())?;
+    big_bytes.extend_from_slice(b"extra");
+
+    // Write both in small chunks, so that the big one spills partway through.
+    for bytes in [&b"hello world"[..], &big_bytes[..]] {
+        let mut writer = conn.blob_writer();
+        for chunk in bytes.chunks(1000) {
+            writer.write_all(chunk)?;
+        }
+        let id = writer.finish()?;
+        assert_eq!(id, blake3::hash(bytes));
+        assert_eq!(conn.get_blob(&id)?, bytes);
+    }
+    let big_id = blake3::hash(&big_bytes);
+    assert!(conn.blob_path(&big_id).exists());
+
+    // Writing a duplicate short-circuits and doesn't leave a tempfile behind.
+    let mut writer = conn.blob_writer();
+    writer.write_all(&big_bytes)?;
+    assert_eq!(writer.finish()?, big_id);
+    assert_eq!(fs::read_dir(dir.path().join("db/blobs"))?.count(), 1);
+
+    // A write that fails to spill poisons the writer, rather than leaving it with data that the
+    // hasher didn't see.
+    let blobs_dir = dir.path().join("db/blobs");
+    let moved_dir = dir.path().join("blobs-moved");
+    let mut writer = conn.blob_writer();
+    writer.write_all(b"hello")?;
+    fs::rename(&blobs_dir, &moved_dir)?;
+    writer.write_all(&big_bytes).unwrap_err();
+    fs::rename(&moved_dir, &blobs_dir)?;
+    writer.write_all(b"world").unwrap_err();
+    writer.finish().unwrap_err();
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlinks() -> anyhow::Result<()> {
+    use std::os::unix::fs::symlink;
+
+    // Test data:
+    // - a: b"foo"
+    // - link -> a
+    // - dangling -> nowhere
+
+    let dir = tempfile::tempdir()?;
+    let mut conn = TreeDb::open(dir.path().join("db"))?;
+    let src = dir.path().join("src");
+    fs::create_dir(&src)?;
+    fs::write(src.join("a"), b"foo")?;
+    symlink("a", src.join("link"))?;
+    symlink("nowhere", src.join("dangling"))?;
+    let root_id = conn.insert_dir(&src)?;
+
+    let mut expected = Tree::new();
+    expected.add_child(
+        "a",
+        &blake3::hash(b"foo"),
+        NodeType::Blob { executable: false },
+    );
+    expected.add_child("link", &blake3::hash(b"a"), NodeType::Symlink);
+    expected.add_child("dangling", &blake3::hash(b"nowhere"), NodeType::Symlink);
+    assert_eq!(conn.get_tree(&root_id)?.unwrap(), expected);
+
+    // The target is part of the tree ID, and distinct from a regular file with the same contents.
+    let mut regular = expected.clone();
+    regular.add_child(
+        "link",
+        &blake3::hash(b"a"),
+        NodeType::Blob { executable: false },
+    );
+    assert_ne!(regular.id(), root_id);
+
+    let dest = dir.path().join("dest");
+    conn.checkout_tree(&root_id, &dest)?;
+    assert_eq!(fs::read_link(dest.join("link"))?, Path::new("a"));
+    assert_eq!(fs::read_link(dest.join("dangling"))?, Path::new("nowhere"));
+    assert_eq!(fs::read(dest.join("link"))?, b"foo");
+
+    // Retarget a link.
+    fs::remove_file(src.join("link"))?;
+    symlink("dangling", src.join("link"))?;
+    let new_root_id = conn.insert_dir(&src)?;
+    assert_eq!(
+        conn.diff_trees(&root_id, &new_root_id)?,
+        vec![DiffEntry::Modified {
+            path: "link".into(),
+            old_id: blake3::hash(b"a"),
+            old_node_type: NodeType::Symlink,
+            new_id: blake3::hash(b"dangling"),
+            new_node_type: NodeType::Symlink,
+        }],
+    );
+    conn.update_checkout(&root_id, &new_root_id, &dest, false)?;
+    assert_eq!(fs::read_link(dest.join("link"))?, Path::new("dangling"));
+
+    Ok(())
+}
+
+#[test]
+fn test_empty_trees() -> anyhow::Result<()> {
+    // Test data:
+    // - a: b"foo"
+    // - lib/ (empty)
+
+    let dir = tempfile::tempdir()?;
+    let mut conn = TreeDb::open(dir.path().join("db"))?;
+    let empty = Tree::new();
+    let empty_id = conn.insert_tree(&empty)?;
+    assert_eq!(empty_id, empty.id());
+    assert_eq!(conn.get_tree(&empty_id)?, Some(Tree::new()));
+    // Unknown trees are still None.
+    assert_eq!(conn.get_tree(&blake3::hash(b"nope"))?, None);
+
+    let src = dir.path().join("src");
+    fs::create_dir_all(src.join("lib"))?;
+    fs::write(src.join("a"), b"foo")?;
+    let root_id = conn.insert_dir(&src)?;
+    let mut root = Tree::new();
+    root.add_child(
+        "a",
+        &blake3::hash(b"foo"),
+        NodeType::Blob { executable: false },
+    );
+    root.add_child("lib", &empty_id, NodeType::Tree);
+    assert_eq!(conn.get_tree(&root_id)?.unwrap(), root);
+
+    let dest = dir.path().join("dest");
+    conn.checkout_tree(&root_id, &dest)?;
+    assert!(fs::read_dir(dest.join("lib"))?.next().is_none());
+    conn.update_checkout(&root_id, &empty_id, &dest, false)?;
+    assert!(fs::read_dir(&dest)?.next().is_none());
+
+    // The empty tree survives GC and can be a ref.
+    conn.set_ref("empty", &empty_id)?;
+    conn.gc(&[empty_id])?;
+    assert_eq!(conn.get_tree(&empty_id)?, Some(Tree::new()));
+
+    Ok(())
+}
+
+#[test]
+fn test_lookup_path() -> anyhow::Result<()> {
+    // Test data:
+    // - src/foo/bar.c: b"int main() {}" (executable)
+    // - README: b"hi"
+
+    let dir = tempfile::tempdir()?;
+    let mut conn = TreeDb::open(dir.path().join("db"))?;
+    let bar_id = conn.insert_blob(b"int main() {}")?;
+    let readme_id = conn.insert_blob(b"hi")?;
+    let mut foo = Tree::new();
+    foo.add_child("bar.c", &bar_id, NodeType::Blob { executable: true });
+    let foo_id = conn.insert_tree(&foo)?;
+    let mut src = Tree::new();
+    src.add_child("foo", &foo_id, NodeType::Tree);
+    let src_id = conn.insert_tree(&src)?;
+    let mut root = Tree::new();
+    root.add_child("src", &src_id, NodeType::Tree);
+    root.add_child("README", &readme_id, NodeType::Blob { executable: false });
+    let root_id = conn.insert_tree(&root)?;
+
+    assert_eq!(
+        conn.lookup_path(&root_id, "src/foo/bar.c")?,
+        (bar_id, NodeType::Blob { executable: true }),
+    );
+    assert_eq!(
+        conn.lookup_path(&root_id, "src/foo/")?,
+        (foo_id, NodeType::Tree)
+    );
+    assert_eq!(conn.lookup_path(&root_id, "")?, (root_id, NodeType::Tree));
+
+    let err = conn.lookup_path(&root_id, "src/baz/bar.c").unwrap_err();
+    assert!(err.to_string().contains("no entry \"baz\" in src"), "{err}");
+    let err = conn.lookup_path(&root_id, "README/x").unwrap_err();
+    assert!(err.to_string().contains("README is not a tree"), "{err}");
+    conn.lookup_path(&blake3::hash(b"nope"), "src").unwrap_err();
+
+    // The public Child accessors.
+    let child = root.get_child("src").unwrap();
+    assert_eq!(child.name(), "src");
+    assert_eq!(*child.id(), src_id);
+    assert_eq!(child.node_type(), NodeType::Tree);
+
+    Ok(())
+}
+
+#[test]
+fn test_edit_tree() -> anyhow::Result<()> {
+    // Test data:
+    // - config.toml: b"x = 1"
+    // - out/app: b"app"
+    // - src/main.c: b"int main() {}"
+
+    let dir = tempfile::tempdir()?;
+    let mut conn = TreeDb::open(dir.path().join("db"))?;
+    let src = dir.path().join("src");
+    fs::create_dir_all(src.join("out"))?;
+    fs::create_dir_all(src.join("src"))?;
+    fs::write(src.join("config.toml"), b"x = 1")?;
+    fs::write(src.join("out/app"), b"app")?;
+    fs::write(src.join("src/main.c"), b"int main() {}")?;
+    let root_id = conn.insert_dir(&src)?;
+    let (src_id, _) = conn.lookup_path(&root_id, "src")?;
+    let new_app_id = conn.insert_blob(b"new app")?;
+
+    // The same edits, applied to the directory and to the tree, give the same result.
+    let new_root_id = conn.edit_tree(
+        &root_id,
+        &[
+            TreeEdit::Set {
+                path: "out/app".into(),
+                id: new_app_id,
+                node_type: NodeType::Blob { executable: true },
+            },
+            TreeEdit::Remove {
+                path: "config.toml".into(),
+            },
+            TreeEdit::Rename {
+                from: "src".into(),
+                to: "lib/src".into(),
+            },
+        ],
+    )?;
+    fs::write(src.join("out/app"), b"new app")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(src.join("out/app"), fs::Permissions::from_mode(0o755))?;
+    }
+    fs::remove_file(src.join("config.toml"))?;
+    fs::create_dir(src.join("lib"))?;
+    fs::rename(src.join("src"), src.join("lib/src"))?;
+    if cfg!(unix) {
+        assert_eq!(conn.insert_dir(&src)?, new_root_id);
+    }
+    // Unchanged subtrees are reused.
+    assert_eq!(conn.lookup_path(&new_root_id, "lib/src")?.0, src_id);
+
+    // Merges keep the entries that are only on one side, and replace blobs.
+    let blob = NodeType::Blob { executable: false };
+    let lib_c_id = conn.insert_blob(b"int lib;")?;
+    let mut overlay_src = Tree::new();
+    overlay_src.add_child("lib.c", &lib_c_id, blob);
+    let mut overlay = Tree::new();
+    overlay.add_child("src", &conn.insert_tree(&overlay_src)?, NodeType::Tree);
+    overlay.add_child("config.toml", &lib_c_id, blob);
+    let overlay_id = conn.insert_tree(&overlay)?;
+    let merge = |path: &str, id: &blake3::Hash| TreeEdit::Merge {
+        path: path.into(),
+        id: *id,
+    };
+    let merged_id = conn.edit_tree(&root_id, &[merge("", &overlay_id)])?;
+    let mut paths = Vec::new();
+    for entry in conn.walk(&merged_id)? {
+        let entry = entry?;
+        paths.push((entry.path, entry.id));
+    }
+    let main_c_id = conn.lookup_path(&root_id, "src/main.c")?.0;
+    let (out_id, _) = conn.lookup_path(&root_id, "out")?;
+    let app_id = conn.lookup_path(&root_id, "out/app")?.0;
+    assert_eq!(
+        paths,
+        [
+            ("config.toml".to_string(), lib_c_id),
+            ("out".to_string(), out_id),
+            ("out/app".to_string(), app_id),
+            ("src".to_string(), paths[3].1),
+            ("src/lib.c".to_string(), lib_c_id),
+            ("src/main.c".to_string(), main_c_id),
+        ],
+    );
+    // Merging at a missing path or a blob is the same as setting it.
+    let set = |path: &str| TreeEdit::Set {
+        path: path.into(),
+        id: overlay_id,
+        node_type: NodeType::Tree,
+    };
+    for path in ["new/dir", "config.toml"] {
+        assert_eq!(
+            conn.edit_tree(&root_id, &[merge(path, &overlay_id)])?,
+            conn.edit_tree(&root_id, &[set(path)])?,
+        );
+    }
+    // Merging a tree into itself changes nothing.
+    assert_eq!(conn.edit_tree(&root_id, &[merge("src", &src_id)])?, root_id);
+    conn.edit_tree(&root_id, &[merge("", &blake3::hash(b"not inserted"))])
+        .unwrap_err();
+
+    // Failed edits are errors.
+    let remove = |path: &str| TreeEdit::Remove { path: path.into() };
+    conn.edit_tree(&root_id, &[remove("nope")]).unwrap_err();
+    conn.edit_tree(&root_id, &[remove("config.toml/x")])
+        .unwrap_err();
+    conn.edit_tree(&root_id, &[remove("")]).unwrap_err();
+    let rename = |from: &str, to: &str| TreeEdit::Rename {
+        from: from.into(),
+        to: to.into(),
+    };
+    conn.edit_tree(&root_id, &[rename("src", "src/inner")])
+        .unwrap_err();
+    conn.edit_tree(&root_id, &[rename("src", "/src//a/b")])
+        .unwrap_err();
+    // Only whole components count as a prefix.
+    conn.edit_tree(&root_id, &[rename("src", "src2/a")])?;
+    conn.edit_tree(
+        &root_id,
+        &[TreeEdit::Set {
+            path: "x".into(),
+            id: blake3::hash(b"not inserted"),
+            node_type: NodeType::Blob { executable: false },
+        }],
+    )
+    .unwrap_err();
+
+    Ok(())
+}
+
+#[test]
+fn test_walk() -> anyhow::Result<()> {
+    // Test data:
+    // - a: b"foo"
+    // - b/c: b"bar"
+    // - b/d/e: b"baz"
+    // - f/ (empty)
+    // - g/c: b"bar"
+
+    let dir = tempfile::tempdir()?;
+    let mut conn = TreeDb::open(dir.path().join("db"))?;
+    let src = dir.path().join("src");
+    fs::create_dir_all(src.join("b/d"))?;
+    fs::create_dir_all(src.join("f"))?;
+    fs::create_dir_all(src.join("g"))?;
+    fs::write(src.join("a"), b"foo")?;
+    fs::write(src.join("b/c"), b"bar")?;
+    fs::write(src.join("b/d/e"), b"baz")?;
+    fs::write(src.join("g/c"), b"bar")?;
+    let root_id = conn.insert_dir(&src)?;
+
+    let paths =
+        |walk: Walk| -> anyhow::Result<Vec<String>> { walk.map(|entry| Ok(entry?.path)).collect() };
+    assert_eq!(
+        paths(conn.walk(&root_id)?)?,
+        ["a", "b", "b/c", "b/d", "b/d/e", "f", "g", "g/c"],
+    );
+    assert_eq!(
+        paths(conn.walk(&root_id)?.post_order(true))?,
+        ["a", "b/c", "b/d/e", "b/d", "b", "f", "g/c", "g"],
+    );
+    assert_eq!(
+        paths(conn.walk(&root_id)?.prune(|entry| entry.path == "b"))?,
+        ["a", "b", "f", "g", "g/c"],
+    );
+
+    // Every entry matches lookup_path.
+    let entries: Vec<WalkEntry> = conn.walk(&root_id)?.collect::<anyhow::Result<_>>()?;
+    for entry in entries {
+        assert_eq!(
+            conn.lookup_path(&root_id, &entry.path)?,
+            (entry.id, entry.node_type),
+        );
+    }
+
+    conn.walk(&blake3::hash(b"nope")).unwrap_err();
+
+    Ok(())
+}
+
+#[test]
+fn test_verify() -> anyhow::Result<()> {
+    // Test data:
+    // - a: b"foo"
+    // - b: <LARGE_BLOB_THRESHOLD random bytes>
+    // - c: <different random bytes>
+    // - d/e: b"bar"
+
+    let dir = tempfile::tempdir()?;
+    let mut conn = TreeDb::open(dir.path().join("db"))?;
+    let src = dir.path().join("src");
+    fs::create_dir_all(src.join("d"))?;
+    fs::write(src.join("a"), b"foo")?;
+    fs::copy(big_blob_tempfile()?.path(), src.join("b"))?;
+    fs::copy(big_blob_tempfile()?.path(), src.join("c"))?;
+    fs::write(src.join("d/e"), b"bar")?;
+    let root_id = conn.insert_dir(&src)?;
+    let outboard_id = conn.insert_file(big_blob_tempfile()?.path())?;
+    assert_eq!(conn.verify(false)?, VerifyReport::default());
+
+    // Corrupt everything.
+    let foo_id = blake3::hash(b"foo");
+    conn.conn.execute(
+        "UPDATE blobs SET data = ? WHERE blob_id = ?",
+        (b"oops", foo_id.as_bytes()),
+    )?;
+    let (b_id, _) = conn.lookup_path(&root_id, "b")?;
+    let b_path = conn.blob_path(&b_id);
+    let mut permissions = fs::metadata(&b_path)?.permissions();
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    fs::set_permissions(&b_path, permissions)?;
+    fs::write(&b_path, b"oops")?;
+    let (c_id, _) = conn.lookup_path(&root_id, "c")?;
+    fs::remove_file(conn.blob_path(&c_id))?;
+    let orphan_path = conn.blob_path(&blake3::hash(b"orphan"));
+    fs::write(&orphan_path, b"orphan")?;
+    // Leftovers from gc and BlobWriter aren't orphans.
+    let blobs_dir = orphan_path.parent().unwrap();
+    fs::write(blobs_dir.join("tmp-leftover"), b"tmp")?;
+    fs::write(
+        blobs_dir.join(format!("{}.garbage", blake3::hash(b"gone").to_hex())),
+        b"garbage",
+    )?;
+    let (d_id, _) = conn.lookup_path(&root_id, "d")?;
+    conn.conn.execute(
+        "UPDATE trees SET child_name = 'renamed' WHERE tree_id = ?",
+        (d_id.as_bytes(),),
+    )?;
+    let bar_id = blake3::hash(b"bar");
+    conn.conn
+        .execute("DELETE FROM blobs WHERE blob_id = ?", (bar_id.as_bytes(),))?;
+    conn.conn.execute(
+        "UPDATE outboards SET data = zeroblob(length(data)) WHERE blob_id = ?",
+        (outboard_id.as_bytes(),),
+    )?;
+
+    let report = conn.verify(false)?;
+    let expected = VerifyReport {
+        corrupt_blobs: vec![foo_id, b_id],
+        missing_blob_files: vec![c_id],
+        corrupt_outboards: vec![outboard_id],
+        corrupt_trees: vec![d_id],
+        dangling_children: vec![(d_id, "renamed".into(), bar_id)],
+        orphaned_files: vec![orphan_path.clone()],
+        quarantine_dir: None,
+    };
+    assert_eq!(report, expected);
+
+    // Repair quarantines the bad objects, and leaves the dangling child for the caller.
+    let report = conn.verify(true)?;
+    let quarantine_dir = dir.path().join("db/quarantine");
+    assert_eq!(report.quarantine_dir.as_deref(), Some(&*quarantine_dir));
+    assert_eq!(
+        fs::read(quarantine_dir.join(foo_id.to_hex().as_str()))?,
+        b"oops"
+    );
+    assert_eq!(
+        fs::read(quarantine_dir.join(b_id.to_hex().as_str()))?,
+        b"oops"
+    );
+    assert!(
+        quarantine_dir
+            .join(orphan_path.file_name().unwrap())
+            .exists()
+    );
+    let report = conn.verify(false)?;
+    assert!(report.corrupt_blobs.is_empty());
+    assert!(report.missing_blob_files.is_empty());
+    assert!(report.corrupt_outboards.is_empty());
+    assert!(report.corrupt_trees.is_empty());
+    assert!(report.orphaned_files.is_empty());
+    // Root's children a, b, c, and d are all gone now.
+    assert_eq!(report.dangling_children.len(), 4);
+
+    // A second repair doesn't overwrite what the first one quarantined.
+    fs::write(&orphan_path, b"orphan 2")?;
+    conn.verify(true)?;
+    let orphan_name = orphan_path.file_name().unwrap().to_string_lossy();
+    assert_eq!(fs::read(quarantine_dir.join(&*orphan_name))?, b"orphan");
+    assert_eq!(
+        fs::read(quarantine_dir.join(format!("{orphan_name}.1")))?,
+        b"orphan 2"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_copy_closure() -> anyhow::Result<()> {
+    // Test data:
+    // - a: b"foo"
+    // - b/c: <LARGE_BLOB_THRESHOLD random bytes>
+    // - d/e: b"bar"
+    // - f -> a
+
+    let dir = tempfile::tempdir()?;
+    let mut src_db = TreeDb::open(dir.path().join("db1"))?;
+    let src = dir.path().join("src");
+    fs::create_dir_all(src.join("b"))?;
+    fs::create_dir_all(src.join("d"))?;
+    fs::write(src.join("a"), b"foo")?;
+    let big_file = big_blob_tempfile()?;
+    fs::copy(big_file.path(), src.join("b/c"))?;
+    fs::write(src.join("d/e"), b"bar")?;
+    #[cfg(unix)]
+    std::os::unix::fs::symlink("a", src.join("f"))?;
+    let root_id = src_db.insert_dir(&src)?;
+
+    // The destination already has d/, so it's skipped.
+    let mut dest_db = TreeDb::open(dir.path().join("db2"))?;
+    dest_db.insert_dir(src.join("d"))?;
+    let stats = src_db.copy_closure(&mut dest_db, &root_id)?;
+    let symlinks = if cfg!(unix) { 1 } else { 0 };
+    assert_eq!(
+        stats,
+        CopyStats {
+            trees: 2,
+            blobs: 2 + symlinks,
+        },
+    );
+    let dest = dir.path().join("dest");
+    dest_db.checkout_tree(&root_id, &dest)?;
+    assert_eq!(dest_db.insert_dir(&dest)?, root_id);
+    assert_eq!(fs::read(dest.join("b/c"))?, fs::read(big_file.path())?);
+    assert_eq!(dest_db.verify(false)?, VerifyReport::default());
+
+    // Copying again is a no-op.
+    assert_eq!(
+        src_db.copy_closure(&mut dest_db, &root_id)?,
+        CopyStats::default()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_bundle() -> anyhow::Result<()> {
+    // Test data:
+    // - a: b"foo"
+    // - b/c: <LARGE_BLOB_THRESHOLD random bytes>
+    // - b/d: b"foo" (executable)
+    // - e/ (empty)
+
+    let dir = tempfile::tempdir()?;
+    let mut src_db = TreeDb::open(dir.path().join("db1"))?;
+    let foo_id = src_db.insert_blob(b"foo")?;
+    let big_file = big_blob_tempfile()?;
+    let big_id = src_db.insert_file(big_file.path())?;
+    let mut b_tree = Tree::new();
+    b_tree.add_child("c", &big_id, NodeType::Blob { executable: false });
+    b_tree.add_child("d", &foo_id, NodeType::Blob { executable: true });
+    let b_id = src_db.insert_tree(&b_tree)?;
+    let empty_id = src_db.insert_tree(&Tree::new())?;
+    let mut root = Tree::new();
+    root.add_child("a", &foo_id, NodeType::Blob { executable: false });
+    root.add_child("b", &b_id, NodeType::Tree);
+    root.add_child("e", &empty_id, NodeType::Tree);
+    let root_id = src_db.insert_tree(&root)?;
+
+    let mut bundle = Vec::new();
+    src_db.export_bundle(&[root_id, b_id], &mut bundle)?;
+    assert_eq!(&bundle[..8], b"TDBUNDLE");
+
+    // Any corruption is caught, and nothing is committed.
+    let mut dest_db = TreeDb::open(dir.path().join("db2"))?;
+    for i in [12, 100, bundle.len() / 2, bundle.len() - 10] {
+        let mut corrupt = bundle.clone();
+        corrupt[i] ^= 1;
+        dest_db.import_bundle(&corrupt[..]).unwrap_err();
+    }
+    dest_db
+        .import_bundle(&bundle[..bundle.len() - 1])
+        .unwrap_err();
+    assert!(!dest_db.contains_blob(foo_id)?);
+    assert!(dest_db.get_tree(&b_id)?.is_none());
+
+    assert_eq!(dest_db.import_bundle(&bundle[..])?, [root_id, b_id]);
+    assert_eq!(dest_db.get_tree(&root_id)?.unwrap(), root);
+    assert_eq!(dest_db.get_blob(&big_id)?, fs::read(big_file.path())?);
+    assert_eq!(dest_db.verify(false)?, VerifyReport::default());
+
+    // A bundle whose closure is incomplete is rejected, and nothing is committed. Here, a bundle
+    // of `b` claims to have `root` as its root.
+    let mut partial = Vec::new();
+    src_db.export_bundle(&[b_id], &mut partial)?;
+    partial[16..48].copy_from_slice(root_id.as_bytes());
+    let mut db3 = TreeDb::open(dir.path().join("db3"))?;
+    db3.import_bundle(&partial[..]).unwrap_err();
+    assert!(!db3.contains_blob(foo_id)?);
+    assert!(db3.get_tree(&b_id)?.is_none());
+
+    Ok(())
+}
+
+#[test]
+fn test_tar() -> anyhow::Result<()> {
+    // Test data:
+    // - a: b"foo" (executable)
+    // - b/c: <LARGE_BLOB_THRESHOLD random bytes>
+    // - b/link -> ../a
+    // - empty/
+
+    let dir = tempfile::tempdir()?;
+    let mut conn = TreeDb::open(dir.path().join("db"))?;
+    let foo_id = conn.insert_blob(b"foo")?;
+    let big_file = big_blob_tempfile()?;
+    let big_id = conn.insert_file(big_file.path())?;
+    let link_id = conn.insert_blob(b"../a")?;
+    let mut b_tree = Tree::new();
+    b_tree.add_child("c", &big_id, NodeType::Blob { executable: false });
+    b_tree.add_child("link", &link_id, NodeType::Symlink);
+    let b_id = conn.insert_tree(&b_tree)?;
+    let empty_id = conn.insert_tree(&Tree::new())?;
+    let mut root = Tree::new();
+    root.add_child("a", &foo_id, NodeType::Blob { executable: true });
+    root.add_child("b", &b_id, NodeType::Tree);
+    root.add_child("empty", &empty_id, NodeType::Tree);
+    let root_id = conn.insert_tree(&root)?;
+
+    // Export is deterministic and round-trips.
+    let mut tarball = Vec::new();
+    conn.export_tar(&root_id, &mut tarball)?;
+    let mut tarball2 = Vec::new();
+    conn.export_tar(&root_id, &mut tarball2)?;
+    assert_eq!(tarball, tarball2);
+    let mut db2 = TreeDb::open(dir.path().join("db2"))?;
+    assert_eq!(db2.import_tar(&tarball[..])?, root_id);
+
+    let mut archive = tar::Archive::new(&tarball[..]);
+    let mut listing = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let header = entry.header();
+        assert_eq!(header.mtime()?, 0);
+        assert_eq!(header.uid()?, 0);
+        listing.push((entry.path()?.to_string_lossy().into_owned(), header.mode()?));
+    }
+    assert_eq!(
+        listing,
+        [
+            ("a".into(), 0o755),
+            ("b/".into(), 0o755),
+            ("b/c".into(), 0o644),
+            ("b/link".into(), 0o777),
+            ("empty/".into(), 0o755),
+        ],
+    );
+
+    // Import handles "./" prefixes, out-of-order entries, and hard links, and rejects paths that
+    // escape the root.
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut append = |path: &str, entry_type: tar::EntryType, mode: u32, data: &[u8]| {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_size(data.len() as u64);
+        builder.append_data(&mut header, path, data)
+    };
+    append(
+        "./b/c",
+        tar::EntryType::Regular,
+        0o600,
+        &fs::read(big_file.path())?,
+    )?;
+    append("./a", tar::EntryType::Regular, 0o700, b"foo")?;
+    append("./b/", tar::EntryType::Directory, 0o700, b"")?;
+    append("./empty/", tar::EntryType::Directory, 0o700, b"")?;
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_size(0);
+    builder.append_link(&mut header, "./b/link", "../a")?;
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Link);
+    header.set_size(0);
+    builder.append_link(&mut header, "./b/hardlink", "./a")?;
+    let tarball = builder.into_inner()?;
+    let imported = conn.import_tar(&tarball[..])?;
+    let expected = conn.edit_tree(
+        &root_id,
+        &[TreeEdit::Set {
+            path: "b/hardlink".into(),
+            id: foo_id,
+            node_type: NodeType::Blob { executable: true },
+        }],
+    )?;
+    assert_eq!(imported, expected);
+
+    // Entries apply in stream order, so the last one for a path wins, but a directory entry
+    // doesn't clear out the files before it.
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut append = |path: &str, entry_type: tar::EntryType, data: &[u8]| {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        builder.append_data(&mut header, path, data)
+    };
+    append("x", tar::EntryType::Regular, b"old")?;
+    append("x", tar::EntryType::Regular, b"foo")?;
+    append("d/f", tar::EntryType::Regular, b"foo")?;
+    append("d/", tar::EntryType::Directory, b"")?;
+    append("y", tar::EntryType::Regular, b"foo")?;
+    append("y/", tar::EntryType::Directory, b"")?;
+    append("z/", tar::EntryType::Directory, b"")?;
+    append("z", tar::EntryType::Regular, b"foo")?;
+    let imported = conn.import_tar(&builder.into_inner()?[..])?;
+    let mut listing = Vec::new();
+    conn.write_manifest(&imported, true, &mut listing)?;
+    assert_eq!(
+        String::from_utf8(listing)?,
+        format!(
+            "blob {foo}\td/f\nblob {foo}\tx\ntree {empty}\ty\nblob {foo}\tz\n",
+            foo = foo_id.to_hex(),
+            empty = empty_id.to_hex(),
+        ),
+    );
+
+    // Hard links can't point to a file that's since been replaced by a directory, or that was
+    // inside a directory that's since been replaced by a file.
+    for (first, second) in [("a", "a/b"), ("d/f", "d")] {
+        let mut builder = tar::Builder::new(Vec::new());
+        for path in [first, second] {
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(0o644);
+            header.set_size(3);
+            builder.append_data(&mut header, path, &b"foo"[..])?;
+        }
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        builder.append_link(&mut header, "h", first)?;
+        let error = conn
+            .import_tar(&builder.into_inner()?[..])
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("unknown file"), "{error}");
+    }
+
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(0);
+    // append_data refuses to write "..", so set the raw name.
+    header.as_old_mut().name[..7].copy_from_slice(b"../evil");
+    header.set_cksum();
+    builder.append(&header, &b""[..])?;
+    conn.import_tar(&builder.into_inner()?[..]).unwrap_err();
+
+    Ok(())
+}
+
+#[test]
+fn test_compression() -> anyhow::Result<()> {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    let dir = tempfile::tempdir()?;
+    let db_path = dir.path().join("db");
+
+    // Start with a database from before the codec column existed.
+    fs::create_dir_all(&db_path)?;
+    rusqlite::Connection::open(db_path.join("db"))?.execute(
+        "CREATE TABLE blobs (blob_id BLOB NOT NULL, data BLOB, PRIMARY KEY (blob_id))",
+        (),
+    )?;
+    let mut conn = TreeDb::open(&db_path)?;
+    let plain_id = conn.insert_blob(b"stored before compression")?;
+    let random_file = big_blob_tempfile()?;
+    let random_id = conn.insert_file(random_file.path())?;
+
+    conn.set_compression(Some(3));
+    let small_bytes = b"abcdefgh".repeat(100);
+    let big_bytes: Vec<u8> = (0..200_000u32)
+        .flat_map(|i| (i % 1000).to_le_bytes())
+        .collect();
+    let big_file = NamedTempFile::new()?;
+    fs::write(big_file.path(), &big_bytes)?;
+    let small_id = conn.insert_blob(&small_bytes)?;
+    let big_id = conn.insert_file(big_file.path())?;
+    assert_eq!(small_id, blake3::hash(&small_bytes));
+    assert_eq!(big_id, blake3::hash(&big_bytes));
+    assert!(fs::metadata(conn.blob_path(&big_id))?.len() < big_bytes.len() as u64);
+    // Incompressible data is stored as-is, even with compression on.
+    let random_bytes = fs::read(random_file.path())?;
+    let mut random_bytes_2 = random_bytes.clone();
+    random_bytes_2[0] ^= 1;
+    let random_id_2 = conn.insert_blob(&random_bytes_2)?;
+    assert_eq!(
+        fs::metadata(conn.blob_path(&random_id_2))?.len(),
+        random_bytes.len() as u64,
+    );
+    let codec_of = |conn: &TreeDb, id: &blake3::Hash| -> anyhow::Result<u8> {
+        Ok(conn.conn.query_row(
+            "SELECT codec FROM blobs WHERE blob_id = ?",
+            (id.as_bytes(),),
+            |row| row.get(0),
+        )?)
+    };
+    assert_eq!(codec_of(&conn, &plain_id)?, 0);
+    assert_eq!(codec_of(&conn, &small_id)?, 1);
+    assert_eq!(codec_of(&conn, &big_id)?, 1);
+    assert_eq!(codec_of(&conn, &random_id_2)?, 0);
+
+    // Blob writers compress too.
+    let mut writer = conn.blob_writer();
+    for chunk in big_bytes.chunks(1000) {
+        writer.write_all(chunk)?;
+    }
+    writer.write_all(b"extra")?;
+    let written_id = writer.finish()?;
+    assert_eq!(codec_of(&conn, &written_id)?, 1);
+
+    // Every read path decompresses, regardless of the current setting.
+    conn.set_compression(None);
+    let cases = [
+        (plain_id, b"stored before compression".to_vec()),
+        (random_id, random_bytes),
+        (small_id, small_bytes),
+        (big_id, big_bytes.clone()),
+        (written_id, [&big_bytes[..], b"extra"].concat()),
+    ];
+    for (id, bytes) in &cases {
+        assert_eq!(&conn.get_blob(id)?, bytes);
+        let out_path = dir.path().join("out");
+        conn.get_file(id, &out_path)?;
+        assert_eq!(&fs::read(&out_path)?, bytes);
+        let mut reader = conn.open_blob(id)?;
+        assert_eq!(reader.len(), bytes.len() as u64);
+        reader.seek(SeekFrom::Start(20))?;
+        let mut buf = [0; 5];
+        reader.read_exact(&mut buf)?;
+        assert_eq!(buf, bytes[20..25]);
+        reader.seek(SeekFrom::Start(3))?;
+        reader.read_exact(&mut buf)?;
+        assert_eq!(buf, bytes[3..8]);
+        reader.seek(SeekFrom::End(-5))?;
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail)?;
+        assert_eq!(tail, bytes[bytes.len() - 5..]);
+    }
+    assert!(conn.verify(false)?.is_ok());
+
+    // Copying keeps the codec.
+    let mut tree = Tree::new();
+    for (i, (id, _)) in cases.iter().enumerate() {
+        tree.add_child(format!("file{i}"), id, NodeType::Blob { executable: false });
+    }
+    let tree_id = conn.insert_tree(&tree)?;
+    let other_dir = tempfile::tempdir()?;
+    let mut other = TreeDb::open(other_dir.path())?;
+    conn.copy_closure(&mut other, &tree_id)?;
+    assert_eq!(codec_of(&other, &big_id)?, 1);
+    assert_eq!(other.get_blob(&big_id)?, big_bytes);
+    assert!(other.verify(false)?.is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn test_chunking() -> anyhow::Result<()> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let dir = tempfile::tempdir()?;
+    let mut conn = TreeDb::open(dir.path())?;
+    conn.set_chunking(true);
+    let chunk_count = |conn: &TreeDb| -> anyhow::Result<u64> {
+        Ok(conn
+            .conn
+            .query_row("SELECT COUNT(*) FROM chunks", (), |row| row.get(0))?)
+    };
+
+    // Two big files that differ by a small insertion in the middle.
+    let mut bytes1 = vec![0u8; 1 << 20];
+    rand::fill(&mut bytes1[..]);
+    let mut bytes2 = bytes1.clone();
+    bytes2.splice(500_000..500_000, *b"a few extra bytes");
+    let file1 = NamedTempFile::new()?;
+    fs::write(file1.path(), &bytes1)?;
+    let id1 = conn.insert_file(file1.path())?;
+    assert_eq!(id1, blake3::hash(&bytes1));
+    let chunks1 = chunk_count(&conn)?;
+    assert!(chunks1 > 1);
+    let id2 = conn.insert_blob(&bytes2)?;
+    assert_eq!(id2, blake3::hash(&bytes2));
+    // Only the chunks around the insertion are new.
+    let new_chunks = chunk_count(&conn)? - chunks1;
+    assert!((1..=3).contains(&new_chunks), "{new_chunks} new chunks");
+    // Nothing goes in the blobs dir.
+    assert_eq!(fs::read_dir(dir.path().join("blobs"))?.count(), 0);
+
+    // Reads reassemble the whole blob, even with chunking off.
+    conn.set_chunking(false);
+    for (id, bytes) in [(id1, &bytes1), (id2, &bytes2)] {
+        assert_eq!(&conn.get_blob(&id)?, bytes);
+        let out_path = dir.path().join("out");
+        conn.get_file(&id, &out_path)?;
+        assert_eq!(&fs::read(&out_path)?, bytes);
+        let mut reader = conn.open_blob(&id)?;
+        assert_eq!(reader.len(), bytes.len() as u64);
+        reader.seek(SeekFrom::Start(499_990))?;
+        let mut buf = vec![0; 100_000];
+        reader.read_exact(&mut buf)?;
+        assert_eq!(buf, bytes[499_990..599_990]);
+    }
+    assert!(conn.verify(false)?.is_ok());
+
+    // Copying keeps blobs chunked.
+    let mut tree = Tree::new();
+    tree.add_child("file1", &id1, NodeType::Blob { executable: false });
+    tree.add_child("file2", &id2, NodeType::Blob { executable: false });
+    let tree_id = conn.insert_tree(&tree)?;
+    let other_dir = tempfile::tempdir()?;
+    let mut other = TreeDb::open(other_dir.path())?;
+    conn.copy_closure(&mut other, &tree_id)?;
+    assert_eq!(chunk_count(&other)?, chunk_count(&conn)?);
+    assert_eq!(other.get_blob(&id2)?, bytes2);
+    assert!(other.verify(false)?.is_ok());
+
+    // Dropping one blob only frees the chunks that the other one doesn't use.
+    let mut tree = Tree::new();
+    tree.add_child("file1", &id1, NodeType::Blob { executable: false });
+    let tree_id = conn.insert_tree(&tree)?;
+    conn.set_gc_grace_period(Duration::ZERO);
+    let stats = conn.gc(&[tree_id])?;
+    assert_eq!(stats.blobs, 1);
+    assert_eq!(stats.chunks, new_chunks);
+    assert_eq!(conn.get_blob(&id1)?, bytes1);
+
+    // Corrupting a chunk makes the blob corrupt.
+    conn.conn
+        .execute("UPDATE chunks SET data = x'00' WHERE rowid = 1", ())?;
+    assert_eq!(conn.verify(false)?.corrupt_blobs, [id1]);
+
+    Ok(())
+}
+
+#[test]
+fn test_read_range() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut conn = TreeDb::open(dir.path())?;
+    let mut bytes = vec![0u8; 300_000];
+    rand::fill(&mut bytes[..]);
+    let file = NamedTempFile::new()?;
+    fs::write(file.path(), &bytes)?;
+    let id = conn.insert_file(file.path())?;
+    let small_id = conn.insert_blob(b"hello world")?;
+
+    for (offset, len) in [
+        (0, 10),
+        (16_380, 10),
+        (100_000, 50_000),
+        (299_990, 10),
+        (0, 300_000),
+        (1234, 0),
+    ] {
+        assert_eq!(
+            conn.read_range(&id, offset, len)?,
+            bytes[offset as usize..][..len as usize],
+        );
+    }
+    assert_eq!(conn.read_range(&small_id, 6, 5)?, b"world");
+    conn.read_range(&id, 299_990, 11).unwrap_err();
+    conn.read_range(&small_id, u64::MAX, 2).unwrap_err();
+
+    // Outboards built while hashing are the same whichever way the blob was inserted.
+    let get_outboard = |conn: &TreeDb| -> rusqlite::Result<Vec<u8>> {
+        conn.conn
+            .query_row("SELECT data FROM outboards", (), |row| row.get(0))
+    };
+    let outboard = get_outboard(&conn)?;
+    for insert in 0..3 {
+        let mut conn2 = TreeDb::open(dir.path().join(format!("db{insert}")))?;
+        let id2 = match insert {
+            0 => conn2.insert_blob(&bytes)?,
+            1 => {
+                let src = dir.path().join("src");
+                fs::create_dir(&src)?;
+                fs::write(src.join("file"), &bytes)?;
+                let tree_id = conn2.insert_dir(&src)?;
+                conn2.lookup_path(&tree_id, "file")?.0
+            }
+            _ => {
+                let mut writer = conn2.blob_writer();
+                for chunk in bytes.chunks(7777) {
+                    std::io::Write::write_all(&mut writer, chunk)?;
+                }
+                writer.finish()?
+            }
+        };
+        assert_eq!(id2, id);
+        assert_eq!(get_outboard(&conn2)?, outboard);
+    }
+
+    // Blobs without an outboard get one on their first read.
+    conn.conn.execute("DELETE FROM outboards", ())?;
+    assert_eq!(conn.read_range(&id, 1000, 10)?, bytes[1000..1010]);
+    assert_eq!(get_outboard(&conn)?, outboard);
+
+    // Corruption is caught in ranges that cover it, but not elsewhere.
+    let path = conn.blob_path(&id);
+    let mut permissions = fs::metadata(&path)?.permissions();
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    fs::set_permissions(&path, permissions)?;
+    let mut corrupt = bytes.clone();
+    corrupt[200_000] ^= 1;
+    fs::write(&path, &corrupt)?;
+    conn.read_range(&id, 199_999, 2).unwrap_err();
+    assert_eq!(conn.read_range(&id, 0, 100_000)?, bytes[..100_000]);
+
+    Ok(())
+}
+
+#[test]
+fn test_action_cache() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut conn = TreeDb::open(dir.path())?;
+    let blob = NodeType::Blob { executable: false };
+    let mut input = Tree::new();
+    input.add_child("main.c", &conn.insert_blob(b"int main() {}")?, blob);
+    let input_id = conn.insert_tree(&input)?;
+    let action = Action {
+        argv: vec!["cc".into(), "-o".into(), "main".into(), "main.c".into()],
+        env: [("PATH".to_string(), "/usr/bin".to_string())].into(),
+        input_tree: input_id,
+        output_paths: vec!["main".into()],
+    };
+
+    // Every part of the action goes into the key.
+    let key = action.key();
+    let mut other = action.clone();
+    other.argv[0] = "gcc".into();
+    assert_ne!(other.key(), key);
+    let mut other = action.clone();
+    other.env.insert("CC".into(), "cc".into());
+    assert_ne!(other.key(), key);
+    let mut other = action.clone();
+    other.input_tree = Tree::new().id();
+    assert_ne!(other.key(), key);
+    let mut other = action.clone();
+    other.output_paths.push("main.o".into());
+    assert_ne!(other.key(), key);
+    // Length prefixes keep the boundaries between strings unambiguous.
+    let mut other = action.clone();
+    other.argv = vec!["cc -o".into(), "main".into(), "main.c".into()];
+    assert_ne!(other.key(), key);
+
+    assert_eq!(conn.get_action_result(&key)?, None);
+    let mut output = Tree::new();
+    output.add_child("main", &conn.insert_blob(b"\x7fELF")?, blob);
+    let result = ActionResult {
+        output_tree: conn.insert_tree(&output)?,
+        exit_code: 0,
+        stdout: conn.insert_blob(b"")?,
+        stderr: conn.insert_blob(b"warning: something")?,
+    };
+    conn.insert_action_result(&key, &result)?;
+    assert_eq!(conn.get_action_result(&key)?, Some(result));
+
+    // Results can only point to objects that exist.
+    let missing = blake3::hash(b"nope");
+    for bad in [
+        ActionResult {
+            output_tree: missing,
+            ..result
+        },
+        ActionResult {
+            stdout: missing,
+            ..result
+        },
+        ActionResult {
+            stderr: missing,
+            ..result
+        },
+    ] {
+        conn.insert_action_result(&key, &bad).unwrap_err();
+    }
+    assert_eq!(conn.get_action_result(&key)?, Some(result));
+
+    // Cached results are GC roots until they're deleted.
+    conn.set_gc_grace_period(Duration::ZERO);
+    conn.gc(&[])?;
+    assert!(conn.get_tree(&result.output_tree)?.is_some());
+    assert_eq!(conn.get_blob(&result.stderr)?, b"warning: something");
+    assert!(conn.get_tree(&input_id)?.is_none());
+    assert!(conn.delete_action_result(&key)?);
+    assert!(!conn.delete_action_result(&key)?);
+    assert_eq!(conn.get_action_result(&key)?, None);
+    conn.gc(&[])?;
+    assert!(conn.get_tree(&result.output_tree)?.is_none());
+    assert!(!conn.contains_blob(result.stderr)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_run_action() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut conn = TreeDb::open(dir.path())?;
+    let blob = NodeType::Blob { executable: false };
+    let mut input = Tree::new();
+    input.add_child("in.txt", &conn.insert_blob(b"hello")?, blob);
+    let input_id = conn.insert_tree(&input)?;
+    let script = "cat in.txt > out.txt
+        mkdir -p gen/sub && echo $GREETING > gen/sub/x && ln -s out.txt gen/link
+        echo to stdout; echo to stderr >&2; exit 3";
+    let action = Action {
+        argv: vec!["/bin/sh".into(), "-c".into(), script.into()],
+        env: [("GREETING".to_string(), "hi".to_string())].into(),
+        input_tree: input_id,
+        output_paths: vec![
+            "gen/link".into(),
+            "out.txt".into(),
+            "gen".into(),
+            "missing".into(),
+        ],
+    };
+    let result = conn.run_action(&action)?;
+    assert_eq!(result.exit_code, 3);
+    assert_eq!(conn.get_blob(&result.stdout)?, b"to stdout\n");
+    assert_eq!(conn.get_blob(&result.stderr)?, b"to stderr\n");
+    // The environment is exactly the action's, and undeclared files aren't captured. Missing
+    // outputs are left out.
+    let output_tree = conn.get_tree(&result.output_tree)?.unwrap();
+    assert_eq!(output_tree.children.len(), 2);
+    let (out_id, out_type) = conn.lookup_path(&result.output_tree, "out.txt")?;
+    assert_eq!(
+        (conn.get_blob(&out_id)?, out_type),
+        (b"hello".to_vec(), blob)
+    );
+    let (x_id, _) = conn.lookup_path(&result.output_tree, "gen/sub/x")?;
+    assert_eq!(conn.get_blob(&x_id)?, b"hi\n");
+    let (link_id, link_type) = conn.lookup_path(&result.output_tree, "gen/link")?;
+    assert_eq!(link_type, NodeType::Symlink);
+    assert_eq!(conn.get_blob(&link_id)?, b"out.txt");
+
+    // The command doesn't inherit the caller's environment.
+    let action = Action {
+        argv: vec!["/bin/sh".into(), "-c".into(), "echo \"[$HOME]\"".into()],
+        env: BTreeMap::new(),
+        input_tree: input_id,
+        output_paths: Vec::new(),
+    };
+    let result = conn.run_action(&action)?;
+    assert_eq!(result.exit_code, 0);
+    assert_eq!(conn.get_blob(&result.stdout)?, b"[]\n");
+    assert_eq!(result.output_tree, Tree::new().id());
+
+    // Output paths can't escape the sandbox.
+    for bad in ["../x", "/etc/passwd", "a/./b", ""] {
+        let action = Action {
+            output_paths: vec![bad.into()],
+            ..action.clone()
+        };
+        conn.run_action(&action).unwrap_err();
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_build() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut conn = TreeDb::open(dir.path())?;
+    let blob = NodeType::Blob { executable: false };
+    let source_tree = |conn: &mut TreeDb, name: &str, contents: &[u8]| {
+        let mut tree = Tree::new();
+        tree.add_child(name, &conn.insert_blob(contents)?, blob);
+        conn.insert_tree(&tree)
+    };
+    let c_lib_src = source_tree(&mut conn, "lib.c", b"c;")?;
+    let rust_lib_src = source_tree(&mut conn, "lib.rs", b"rust;")?;
+    let c_bin_src = source_tree(&mut conn, "main.c", b"main;")?;
+    let target =
+        |name: &str, inputs: Vec<(&str, TargetInput)>, script: &str, output: &str| Target {
+            name: name.into(),
+            inputs: inputs
+                .into_iter()
+                .map(|(path, input)| (path.to_string(), input))
+                .collect(),
+            argv: vec!["/bin/sh".into(), "-c".into(), script.into()],
+            env: BTreeMap::new(),
+            output_paths: vec![output.into()],
+        };
+    let dep = |name: &str| TargetInput::Target(name.into());
+    // The same shape as the example projects in this repo, with cat standing in for compilers.
+    let graph = |rust_lib_src, rust_lib_script: &str| {
+        vec![
+            target(
+                "rust_bin",
+                vec![("rust_lib", dep("rust_lib")), ("c_lib", dep("c_lib"))],
+                "cat rust_lib/librust_lib.a c_lib/lib.o > rust_bin",
+                "rust_bin",
+            ),
+            target(
+                "rust_lib",
+                vec![
+                    ("src", TargetInput::Tree(rust_lib_src)),
+                    ("c_lib", dep("c_lib")),
+                ],
+                rust_lib_script,
+                "librust_lib.a",
+            ),
+            target(
+                "c_lib",
+                vec![("src", TargetInput::Tree(c_lib_src))],
+                "cat src/lib.c > lib.o",
+                "lib.o",
+            ),
+            target(
+                "c_bin",
+                vec![
+                    ("src", TargetInput::Tree(c_bin_src)),
+                    ("rust_lib", dep("rust_lib")),
+                    ("c_lib", dep("c_lib")),
+                ],
+                "cat src/main.c rust_lib/librust_lib.a c_lib/lib.o > c_bin",
+                "c_bin",
+            ),
+        ]
+    };
+    let build_rust_lib = "cat src/lib.rs c_lib/lib.o > librust_lib.a";
+    let options = BuildOptions {
+        jobs: 2,
+        keep_going: false,
+    };
+    let output = |conn: &mut TreeDb, outcome: &TargetOutcome, path: &str| {
+        let (id, _) = conn.lookup_path(&outcome.result().unwrap().output_tree, path)?;
+        conn.get_blob(&id)
+    };
+
+    let outcomes = conn.build(&graph(rust_lib_src, build_rust_lib), &options)?;
+    assert_eq!(outcomes.len(), 4);
+    assert!(
+        outcomes
+            .values()
+            .all(|outcome| matches!(outcome, TargetOutcome::Built(_)))
+    );
+    assert_eq!(
+        output(&mut conn, &outcomes["rust_bin"], "rust_bin")?,
+        b"rust;c;c;"
+    );
+    assert_eq!(
+        output(&mut conn, &outcomes["c_bin"], "c_bin")?,
+        b"main;rust;c;c;"
+    );
+
+    // Nothing runs the second time.
+    let cached = conn.build(&graph(rust_lib_src, build_rust_lib), &options)?;
+    for (name, outcome) in &cached {
+        assert_eq!(
+            *outcome,
+            TargetOutcome::Cached(*outcomes[name].result().unwrap())
+        );
+    }
+
+    // Changing a source only rebuilds what depends on it.
+    let new_rust_lib_src = source_tree(&mut conn, "lib.rs", b"rust2;")?;
+    let outcomes = conn.build(&graph(new_rust_lib_src, build_rust_lib), &options)?;
+    assert!(matches!(outcomes["c_lib"], TargetOutcome::Cached(_)));
+    assert!(matches!(outcomes["rust_lib"], TargetOutcome::Built(_)));
+    assert_eq!(
+        output(&mut conn, &outcomes["rust_bin"], "rust_bin")?,
+        b"rust2;c;c;"
+    );
+
+    // A failure skips everything downstream. Without keep_going, nothing new starts either.
+    let mut failing = graph(rust_lib_src, "exit 1");
+    failing.push(target(
+        "docs",
+        vec![("c_lib", dep("c_lib"))],
+        "cat c_lib/lib.o > docs",
+        "docs",
+    ));
+    let options = BuildOptions {
+        jobs: 1,
+        keep_going: false,
+    };
+    let outcomes = conn.build(&failing, &options)?;
+    assert!(matches!(outcomes["c_lib"], TargetOutcome::Cached(_)));
+    assert!(matches!(outcomes["rust_lib"], TargetOutcome::Failed(result) if result.exit_code == 1));
+    for name in ["rust_bin", "c_bin", "docs"] {
+        assert_eq!(outcomes[name], TargetOutcome::Skipped);
+    }
+    let options = BuildOptions {
+        jobs: 1,
+        keep_going: true,
+    };
+    let outcomes = conn.build(&failing, &options)?;
+    assert!(matches!(outcomes["docs"], TargetOutcome::Built(_)));
+    assert_eq!(outcomes["c_bin"], TargetOutcome::Skipped);
+    // Failures aren't cached, and neither are successes with missing outputs.
+    let outcomes = conn.build(&graph(rust_lib_src, "true"), &options)?;
+    assert!(matches!(outcomes["rust_lib"], TargetOutcome::Failed(result) if result.exit_code == 0));
+    assert!(matches!(
+        conn.build(&failing, &options)?["rust_lib"],
+        TargetOutcome::Failed(_)
+    ));
+
+    // Malformed graphs are rejected.
+    let mut duplicate = graph(rust_lib_src, build_rust_lib);
+    duplicate.push(duplicate[0].clone());
+    conn.build(&duplicate, &options).unwrap_err();
+    let mut unknown = graph(rust_lib_src, build_rust_lib);
+    unknown[0].inputs.push(("x".into(), dep("nope")));
+    conn.build(&unknown, &options).unwrap_err();
+    let mut cycle = graph(rust_lib_src, build_rust_lib);
+    cycle[2].inputs.push(("x".into(), dep("rust_bin")));
+    conn.build(&cycle, &options).unwrap_err();
+
+    Ok(())
+}
+
+#[test]
+fn test_build_files() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut conn = TreeDb::open(dir.path())?;
+    let workspace = tempfile::tempdir()?;
+    let write = |path: &str, contents: &str| -> anyhow::Result<()> {
+        let path = workspace.path().join(path);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, contents)?;
+        Ok(())
+    };
+    write(
+        "lib/BUILD.toml",
+        r#"
+# Comments and blank lines are ignored.
+[target.lib]
+sources = ["*.txt"]
+command = "cat {sources} > {outputs}"  # Trailing comments too.
+outputs = ['lib.out']
+"#,
+    )?;
+    write("lib/a.txt", "a")?;
+    write("lib/b b.txt", "b")?;
+    write("lib/c.md", "c")?;
+    write(
+        "app/BUILD.toml",
+        r#"
+[target.app]
+sources = [
+    "src/**/*.in",
+]
+deps = ["lib:lib"]
+command = """
+cat {sources} lib/lib.out > {outputs} \
+  && echo "$GREETING {{}}" >> {dir}/app.out"""
+outputs = ["app.out"]
+env = { GREETING = "hi" }
+
+[target.check]
+deps = [":app"]
+command = "cmp app/app.out app/app.out && cp app/app.out {outputs}"
+outputs = ["check.out"]
+"#,
+    )?;
+    write("app/src/x.in", "x")?;
+    write("app/src/deep/y.in", "y")?;
+    // Hidden and cache directories are skipped.
+    write(".git/BUILD.toml", "nonsense")?;
+    write("app/target/CACHEDIR.TAG", "")?;
+    write("app/target/BUILD.toml", "nonsense")?;
+    write("app/target/src/z.in", "z")?;
+
+    let targets = conn.load_build_files(workspace.path())?;
+    let names: Vec<&str> = targets.iter().map(|target| target.name.as_str()).collect();
+    assert_eq!(names, ["app:app", "app:check", "lib:lib"]);
+    assert_eq!(
+        targets[0].argv[2],
+        "cat app/src/deep/y.in app/src/x.in lib/lib.out > app/app.out \
+         && echo \"$GREETING {}\" >> app/app.out",
+    );
+    let outcomes = conn.build(&targets, &BuildOptions::default())?;
+    let result = outcomes["app:check"].result().unwrap();
+    assert!(matches!(outcomes["app:check"], TargetOutcome::Built(_)));
+    let (id, _) = conn.lookup_path(&result.output_tree, "app/check.out")?;
+    assert_eq!(conn.get_blob(&id)?, b"yxabhi {}\n");
+
+    // Errors point at the file and line.
+    for (contents, error) in [
+        (
+            "command = \"x\"",
+            "app/BUILD.toml:1: unknown field `command`, expected `target`",
+        ),
+        (
+            "[target.a]\n\ncommand = 3",
+            "app/BUILD.toml:3: invalid type: integer `3`, expected a string",
+        ),
+        (
+            "[target.a]\ncommand = \"x",
+            "app/BUILD.toml:2: invalid basic string",
+        ),
+        (
+            "[target.a]\noutputs = [\"a\"]",
+            "app/BUILD.toml:1: missing field `command`",
+        ),
+        (
+            "[target.a]\ncommand = \"x\"\noutputs = [\"a\"]\nsauces = []",
+            "app/BUILD.toml:4: unknown field `sauces`",
+        ),
+        (
+            "[target.a]\ncommand = \"x\"\noutputs = [\"a\"]\ndeps = [\":b\"]",
+            "app/BUILD.toml:4: unknown target \":b\"",
+        ),
+        (
+            "[target.a]\ncommand = \"x\"\noutputs = [\"a\"]\nsources = [\"*.z\"]",
+            "app/BUILD.toml:4: \"*.z\" doesn't match any files",
+        ),
+        (
+            "[target.a]\ncommand = \"{out}\"\noutputs = [\"a\"]",
+            "app/BUILD.toml:2: unknown placeholder {out}",
+        ),
+        (
+            "[target.a]\noutputs = [\"a\"]\ncommand = \"\"\"\nx \\\n  {out}\"\"\"",
+            "app/BUILD.toml:3: unknown placeholder {out}",
+        ),
+        (
+            "[target.a]\ncommand = \"x\"\noutputs = [\"../a\"]",
+            "app/BUILD.toml:3: output \"../a\"",
+        ),
+    ] {
+        write("app/BUILD.toml", contents)?;
+        let message = conn
+            .load_build_files(workspace.path())
+            .unwrap_err()
+            .to_string();
+        assert!(
+            message.starts_with(error),
+            "{message:?} should start with {error:?}"
+        );
+    }
+
+    // The build files for the example projects in this repo load.
+    let repo = Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap();
+    let targets = conn.load_build_files(repo)?;
+    let names: Vec<&str> = targets.iter().map(|target| target.name.as_str()).collect();
+    assert_eq!(
+        names,
+        [
+            "c_bin:main",
+            "c_bin:run",
+            "c_lib:files",
+            "c_lib:lib",
+            "rust_bin:main",
+            "rust_lib:lib",
+            "rust_lib:windows",
+            "rust_lib:header",
+        ],
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_stats() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut conn = TreeDb::open(dir.path())?;
+    assert_eq!(conn.stats()?, StoreStats::default());
+
+    let small_id = conn.insert_blob(b"foo")?;
+    let big_file = big_blob_tempfile()?;
+    let big_id = conn.insert_file(big_file.path())?;
+    conn.set_compression(Some(3));
+    conn.insert_blob(&[0; 1000])?;
+    let mut tree = Tree::new();
+    tree.add_child("a", &small_id, NodeType::Blob { executable: false });
+    tree.add_child("b", &big_id, NodeType::Blob { executable: false });
+    let tree_id = conn.insert_tree(&tree)?;
+    conn.set_ref("main", &tree_id)?;
+
+    let stats = conn.stats()?;
+    assert_eq!(
+        stats,
+        StoreStats {
+            blobs: 3,
+            large_blobs: 1,
+            compressed_blobs: 1,
+            chunked_blobs: 0,
+            chunks: 0,
+            trees: 1,
+            refs: 1,
+            action_results: 0,
+            stored_bytes: stats.stored_bytes,
+        },
+    );
+    // The compressed blob takes up less than its 1000 bytes.
+    assert!(stats.stored_bytes > 3 + LARGE_BLOB_THRESHOLD as u64);
+    assert!(stats.stored_bytes < 3 + LARGE_BLOB_THRESHOLD as u64 + 1000);
+
+    // Files in the blobs dir that aren't blobs don't count.
+    let blobs_dir = conn.blob_path(&big_id).parent().unwrap().to_owned();
+    fs::write(blobs_dir.join("tmp-leftover"), [0; 100])?;
+    fs::write(
+        blobs_dir.join(format!("{}.garbage", small_id.to_hex())),
+        [0; 100],
+    )?;
+    assert_eq!(conn.stats()?, stats);
+
+    Ok(())
+}
+
+#[test]
+fn test_manifest() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut conn = TreeDb::open(dir.path())?;
+    let empty_id = conn.insert_tree(&Tree::new())?;
+    let foo_id = conn.insert_blob(b"foo")?;
+    let link_id = conn.insert_blob(b"../foo")?;
+    let mut inner = Tree::new();
+    inner.add_child("foo", &foo_id, NodeType::Blob { executable: true });
+    inner.add_child("link", &link_id, NodeType::Symlink);
+    inner.add_child("empty", &empty_id, NodeType::Tree);
+    let inner_id = conn.insert_tree(&inner)?;
+    let mut root = Tree::new();
+    root.add_child("foo", &foo_id, NodeType::Blob { executable: false });
+    root.add_child("dir", &inner_id, NodeType::Tree);
+    root.add_child("empty", &empty_id, NodeType::Tree);
+    let root_id = conn.insert_tree(&root)?;
+
+    let mut listing = Vec::new();
+    conn.write_manifest(&root_id, false, &mut listing)?;
+    assert_eq!(
+        String::from_utf8(listing)?,
+        format!(
+            "tree {inner}\tdir\ntree {empty}\tempty\nblob {foo}\tfoo\n",
+            inner = inner_id.to_hex(),
+            empty = empty_id.to_hex(),
+            foo = foo_id.to_hex(),
+        ),
+    );
+
+    // A recursive listing round-trips, including empty trees and symlinks.
+    let mut manifest = Vec::new();
+    conn.write_manifest(&root_id, true, &mut manifest)?;
+    assert_eq!(
+        String::from_utf8(manifest.clone())?,
+        format!(
+            "tree {empty}\tdir/empty\nexec {foo}\tdir/foo\nsymlink {link}\tdir/link\n\
+             tree {empty}\tempty\nblob {foo}\tfoo\n",
+            empty = empty_id.to_hex(),
+            foo = foo_id.to_hex(),
+            link = link_id.to_hex(),
+        ),
+    );
+    assert_eq!(conn.insert_manifest(&manifest[..])?, root_id);
+
+    // So does the empty tree.
+    let mut manifest = Vec::new();
+    conn.write_manifest(&empty_id, true, &mut manifest)?;
+    assert!(manifest.is_empty());
+    assert_eq!(conn.insert_manifest(&manifest[..])?, empty_id);
+
+    // Names that would break the line format are escaped.
+    let mut odd = Tree::new();
+    for name in ["new\nline", "back\\n", "cr\r", "\ttab"] {
+        odd.add_child(name, &foo_id, NodeType::Blob { executable: false });
+    }
+    odd.add_child("a\nb", &inner_id, NodeType::Tree);
+    let odd_id = conn.insert_tree(&odd)?;
+    let mut manifest = Vec::new();
+    conn.write_manifest(&odd_id, true, &mut manifest)?;
+    let manifest = String::from_utf8(manifest)?;
+    assert_eq!(manifest.lines().count(), 7);
+    assert!(manifest.contains("\tback\\\\n\n"));
+    assert!(manifest.contains("\ta\\nb/foo\n"));
+    assert_eq!(conn.insert_manifest(manifest.as_bytes())?, odd_id);
+    let bad_escape = format!("blob {}\tfoo\\x\n", foo_id.to_hex());
+    conn.insert_manifest(bad_escape.as_bytes()).unwrap_err();
+
+    let error = conn
+        .insert_manifest(&b"\nblob nope\tfoo\n"[..])
+        .unwrap_err();
+    assert_eq!(
+        format!("{error:#}").split(':').next(),
+        Some("manifest line 2")
+    );
+    conn.write_manifest(&foo_id, false, &mut Vec::new())
+        .unwrap_err();
+
+    Ok(())
+}
@@ -0,0 +1,214 @@
+use crate::compress::Codec;
+use crate::gc;
+use crate::{NodeType, Tree, TreeDb, insert_tree_rows, tree_exists};
+use anyhow::{Context, bail};
+use rusqlite::{OptionalExtension, TransactionBehavior::Immediate};
+use std::collections::HashSet;
+use std::fs;
+
+/// What [`TreeDb::copy_closure`] copied.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CopyStats {
+    pub trees: u64,
+    pub blobs: u64,
+}
+
+impl TreeDb {
+    /// Copies the tree `root_id` and everything it references into `other`, skipping any
+    /// subtrees and blobs that `other` already has. Large blobs are reflinked between the blobs
+    /// dirs if possible. Everything is inserted into `other` in a single transaction.
+    ///
+    /// Objects are copied as-is without being re-hashed. Use [`verify`](Self::verify) if the
+    /// source might be corrupt.
+    pub fn copy_closure(
+        &mut self,
+        other: &mut TreeDb,
+        root_id: &blake3::Hash,
+    ) -> anyhow::Result<CopyStats> {
+        // Find everything that's missing, without taking the write lock on `other`. Trees are
+        // collected in post-order, so that children get inserted before their parents.
+        let mut missing_trees: Vec<Tree> = Vec::new();
+        let mut missing_blobs: Vec<blake3::Hash> = Vec::new();
+        let mut visited = HashSet::new();
+        self.find_missing(
+            other,
+            root_id,
+            &mut visited,
+            &mut missing_trees,
+            &mut missing_blobs,
+        )?;
+
+        // Deferred transactions are vulnerable to BUSY errors if there are concurrent writers.
+        // See: https://fractaledmind.github.io/2024/04/15/sqlite-on-rails-the-how-and-why-of-optimal-performance/
+        let other_blobs_dir = other.blobs_dir.clone();
+        let tx = other.conn.transaction_with_behavior(Immediate)?;
+        let mut stats = CopyStats::default();
+        let mut copied_paths = Vec::new();
+        for blob_id in &missing_blobs {
+            // Something else might've inserted this in the meantime.
+            gc::mark_recent(&tx, blob_id)?;
+            let exists: u64 = tx.query_row(
+                "SELECT COUNT(*) FROM blobs WHERE blob_id = ?",
+                (blob_id.as_bytes(),),
+                |row| row.get(0),
+            )?;
+            if exists == 1 {
+                continue;
+            }
+            // Blobs keep their codec, so compressed blobs are copied without recompressing them.
+            let row: Option<(Option<Vec<u8>>, u8)> = self
+                .conn
+                .query_row(
+                    "SELECT data, codec FROM blobs WHERE blob_id = ?",
+                    (blob_id.as_bytes(),),
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()?;
+            match row {
+                None => bail!("blob {} doesn't exist", blob_id),
+                // Small blobs go in the blobs table.
+                Some((Some(data), codec)) => {
+                    tx.execute(
+                        "INSERT INTO blobs (blob_id, data, codec) VALUES (?, ?, ?)",
+                        (blob_id.as_bytes(), data, codec),
+                    )?;
+                }
+                // Chunked blobs copy their chunk lists, and any chunks that are missing.
+                Some((None, codec)) if codec == Codec::Chunked.to_column() => {
+                    tx.execute(
+                        "INSERT INTO blobs (blob_id, data, codec) VALUES (?, NULL, ?)",
+                        (blob_id.as_bytes(), codec),
+                    )?;
+                    let mut query = self.conn.prepare(
+                        "SELECT offset, len, chunk_id FROM blob_chunks WHERE blob_id = ?",
+                    )?;
+                    let mut rows = query.query((blob_id.as_bytes(),))?;
+                    while let Some(row) = rows.next()? {
+                        let offset: u64 = row.get(0)?;
+                        let len: u64 = row.get(1)?;
+                        let chunk_id: [u8; 32] = row.get(2)?;
+                        // Only read the chunks that `other` doesn't share already.
+                        let chunk_exists: u64 = tx.query_row(
+                            "SELECT COUNT(*) FROM chunks WHERE chunk_id = ?",
+                            (chunk_id,),
+                            |row| row.get(0),
+                        )?;
+                        if chunk_exists == 0 {
+                            let chunk: Option<(Vec<u8>, u8)> = self
+                                .conn
+                                .query_row(
+                                    "SELECT data, codec FROM chunks WHERE chunk_id = ?",
+                                    (chunk_id,),
+                                    |row| Ok((row.get(0)?, row.get(1)?)),
+                                )
+                                .optional()?;
+                            let Some((data, chunk_codec)) = chunk else {
+                                bail!(
+                                    "chunk {} of blob {} doesn't exist",
+                                    blake3::Hash::from(chunk_id),
+                                    blob_id,
+                                );
+                            };
+                            tx.execute(
+                                "INSERT INTO chunks (chunk_id, data, codec) VALUES (?, ?, ?)",
+                                (chunk_id, data, chunk_codec),
+                            )?;
+                        }
+                        tx.execute(
+                            "INSERT INTO blob_chunks (blob_id, offset, len, chunk_id)
+                             VALUES (?, ?, ?, ?)",
+                            (blob_id.as_bytes(), offset, len, chunk_id),
+                        )?;
+                    }
+                }
+                // Large blobs go in the blobs dir. As in insert_file, the IMMEDIATE transaction
+                // excludes other writers, so we can copy directly to the final path.
+                Some((None, codec)) => {
+                    tx.execute(
+                        "INSERT INTO blobs (blob_id, data, codec) VALUES (?, NULL, ?)",
+                        (blob_id.as_bytes(), codec),
+                    )?;
+                    let source = self.blob_path(blob_id);
+                    let destination = other_blobs_dir.join(blob_id.to_hex().as_str());
+                    if fs::exists(&destination)? {
+                        // A previous insert failed before committing. reflink_or_copy() requires
+                        // the destination to be clear.
+                        fs::remove_file(&destination)?;
+                    }
+                    reflink_copy::reflink_or_copy(&source, &destination).with_context(|| {
+                        format!(
+                            "failed to copy {} to {}",
+                            source.to_string_lossy(),
+                            destination.to_string_lossy(),
+                        )
+                    })?;
+                    copied_paths.push(destination);
+                }
+            }
+            // Copy the outboard too, if there is one, rather than recomputing it.
+            let outboard: Option<Vec<u8>> = self
+                .conn
+                .query_row(
+                    "SELECT data FROM outboards WHERE blob_id = ?",
+                    (blob_id.as_bytes(),),
+                    |row| row.get(0),
+                )
+                .optional()?;
+            if let Some(outboard) = outboard {
+                tx.execute(
+                    "INSERT OR REPLACE INTO outboards (blob_id, data) VALUES (?, ?)",
+                    (blob_id.as_bytes(), outboard),
+                )?;
+            }
+            stats.blobs += 1;
+        }
+        for tree in &missing_trees {
+            if !tree_exists(&tx, &tree.id())? {
+                insert_tree_rows(&tx, tree)?;
+                stats.trees += 1;
+            }
+        }
+
+        // Commit!
+        tx.commit()?;
+
+        // Finally, make the copied files read-only. Copying preserves the source's read-only
+        // permissions, but reflinking might not.
+        for path in copied_paths {
+            let mut permissions = fs::metadata(&path)?.permissions();
+            permissions.set_readonly(true);
+            fs::set_permissions(&path, permissions)?;
+        }
+        Ok(stats)
+    }
+
+    fn find_missing(
+        &mut self,
+        other: &TreeDb,
+        tree_id: &blake3::Hash,
+        visited: &mut HashSet<blake3::Hash>,
+        missing_trees: &mut Vec<Tree>,
+        missing_blobs: &mut Vec<blake3::Hash>,
+    ) -> anyhow::Result<()> {
+        if !visited.insert(*tree_id) || tree_exists(&other.conn, tree_id)? {
+            return Ok(());
+        }
+        let Some(tree) = self.get_tree(tree_id)? else {
+            bail!("tree {} doesn't exist", tree_id);
+        };
+        for child in tree.iter() {
+            match child.node_type {
+                NodeType::Blob { .. } | NodeType::Symlink => {
+                    if visited.insert(*child.id) && !other.contains_blob(*child.id)? {
+                        missing_blobs.push(*child.id);
+                    }
+                }
+                NodeType::Tree => {
+                    self.find_missing(other, child.id, visited, missing_trees, missing_blobs)?;
+                }
+            }
+        }
+        missing_trees.push(tree);
+        Ok(())
+    }
+}
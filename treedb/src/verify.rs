@@ -0,0 +1,322 @@
+use crate::chunk::ChunkedReader;
+use crate::compress::{self, Codec};
+use crate::gc::is_blob_file_name;
+use crate::outboard::{self, OutboardEncoder};
+use crate::{Tree, TreeDb, parse_node_type};
+use anyhow::Context;
+use rayon::prelude::*;
+use rusqlite::{OptionalExtension, TransactionBehavior::Immediate};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The problems found by [`TreeDb::verify`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Blobs whose contents don't hash to their ID, whether they're stored in the blobs table, in
+    /// the blobs dir, or as chunks. Chunked blobs with missing chunks count as corrupt too.
+    pub corrupt_blobs: Vec<blake3::Hash>,
+    /// Large blobs whose rows have NULL data but whose files are missing from the blobs dir.
+    pub missing_blob_files: Vec<blake3::Hash>,
+    /// Large blobs whose contents are fine, but whose outboards don't match them. A missing
+    /// outboard isn't a problem, since [`TreeDb::read_range`] recomputes it.
+    pub corrupt_outboards: Vec<blake3::Hash>,
+    /// Trees whose rows don't hash to their ID.
+    pub corrupt_trees: Vec<blake3::Hash>,
+    /// `(tree_id, child_name, child_id)` for children that don't exist.
+    pub dangling_children: Vec<(blake3::Hash, String, blake3::Hash)>,
+    /// Files in the blobs dir that are named like blobs but don't belong to any large blob.
+    pub orphaned_files: Vec<PathBuf>,
+    /// Where bad objects were moved, if repair was requested and anything was found.
+    pub quarantine_dir: Option<PathBuf>,
+}
+
+impl VerifyReport {
+    /// True if no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.corrupt_blobs.is_empty()
+            && self.missing_blob_files.is_empty()
+            && self.corrupt_outboards.is_empty()
+            && self.corrupt_trees.is_empty()
+            && self.dangling_children.is_empty()
+            && self.orphaned_files.is_empty()
+    }
+}
+
+// Small blobs are hashed in batches of this many rows, to bound memory use.
+const SMALL_BLOB_BATCH: usize = 1024;
+
+// Hashes one batch of small blobs in parallel, and adds the corrupt ones to `corrupt`. Blobs that
+// don't decompress, or have an unknown codec, are corrupt too.
+fn check_small_blobs(
+    batch: &mut Vec<(blake3::Hash, Vec<u8>, u8)>,
+    corrupt: &mut Vec<blake3::Hash>,
+) {
+    corrupt.par_extend(batch.par_drain(..).filter_map(|(id, data, codec)| {
+        let decoded =
+            Codec::from_column(codec).and_then(|codec| compress::decode_bytes(data, codec));
+        let ok = decoded.is_ok_and(|data| blake3::hash(&data) == id);
+        (!ok).then_some(id)
+    }));
+}
+
+// Picks a path in the quarantine dir that doesn't exist yet, so that repeated repairs don't
+// overwrite what earlier ones kept. The plain name is used if it's free.
+fn quarantine_path(quarantine_dir: &Path, name: &str) -> anyhow::Result<PathBuf> {
+    fs::create_dir_all(quarantine_dir)?;
+    let mut path = quarantine_dir.join(name);
+    let mut n = 0;
+    while fs::symlink_metadata(&path).is_ok() {
+        n += 1;
+        path = quarantine_dir.join(format!("{name}.{n}"));
+    }
+    Ok(path)
+}
+
+fn move_to_quarantine(source: &Path, quarantine_dir: &Path) -> anyhow::Result<()> {
+    let name = source.file_name().expect("file name").to_string_lossy();
+    let destination = quarantine_path(quarantine_dir, &name)?;
+    fs::rename(source, &destination).with_context(|| {
+        format!(
+            "failed to move {} to {}",
+            source.to_string_lossy(),
+            destination.to_string_lossy(),
+        )
+    })
+}
+
+impl TreeDb {
+    /// Re-hashes every blob and tree, checks that every tree's children exist, and checks the
+    /// blobs dir for missing and orphaned files. Large blobs are hashed in parallel, and their
+    /// outboards are rebuilt and compared with the stored ones. This holds
+    /// the write lock for the whole check, so that in-progress inserts don't look like problems.
+    ///
+    /// If `repair` is true, corrupt blobs, blobs with missing files, corrupt outboards, and corrupt
+    /// trees are deleted from the database, and bad or orphaned files are moved to a `quarantine` directory next to
+    /// the blobs dir. Dangling children aren't repaired, since the fix for those is to reinsert
+    /// the missing objects.
+    pub fn verify(&mut self, repair: bool) -> anyhow::Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let quarantine_dir = self
+            .blobs_dir
+            .parent()
+            .expect("blobs dir has a parent")
+            .join("quarantine");
+        let blobs_dir = &self.blobs_dir;
+        let blob_path = |blob_id: &blake3::Hash| blobs_dir.join(blob_id.to_hex().as_str());
+        let tx = self.conn.transaction_with_behavior(Immediate)?;
+
+        // Check blobs. Small ones are hashed in batches as we read them.
+        let mut large_blobs = Vec::new();
+        let mut chunked_blobs = Vec::new();
+        {
+            let mut query = tx.prepare("SELECT blob_id, data, codec FROM blobs")?;
+            let mut rows = query.query(())?;
+            let mut batch = Vec::new();
+            while let Some(row) = rows.next()? {
+                let blob_id: blake3::Hash = row.get::<_, [u8; 32]>(0)?.into();
+                let codec: u8 = row.get(2)?;
+                match row.get::<_, Option<Vec<u8>>>(1)? {
+                    Some(data) => {
+                        batch.push((blob_id, data, codec));
+                        if batch.len() == SMALL_BLOB_BATCH {
+                            check_small_blobs(&mut batch, &mut report.corrupt_blobs);
+                        }
+                    }
+                    None if codec == Codec::Chunked.to_column() => chunked_blobs.push(blob_id),
+                    None => large_blobs.push((blob_id, codec)),
+                }
+            }
+            check_small_blobs(&mut batch, &mut report.corrupt_blobs);
+        }
+        // Large blobs are hashed along with their outboards. Only a hash of each outboard is kept
+        // for comparing with the stored one, to bound memory use.
+        let mut rebuilt_outboards = Vec::new();
+        // Chunked blobs are reassembled and hashed one at a time. Missing or corrupt chunks make
+        // the blob corrupt.
+        for blob_id in chunked_blobs {
+            let mut encoder = OutboardEncoder::new();
+            let hashed = ChunkedReader::new(&tx, &blob_id)
+                .and_then(|mut reader| Ok(std::io::copy(&mut reader, &mut encoder)?));
+            let (root, outboard) = encoder.finalize();
+            if hashed.is_err() || root != blob_id {
+                report.corrupt_blobs.push(blob_id);
+            } else {
+                rebuilt_outboards.push((blob_id, blake3::hash(&outboard)));
+            }
+        }
+        let large_results: Vec<(blake3::Hash, Option<Option<blake3::Hash>>)> = large_blobs
+            .par_iter()
+            .map(|&(blob_id, codec)| {
+                let path = blob_path(&blob_id);
+                if !path.exists() {
+                    return Ok((blob_id, None));
+                }
+                let (root, outboard) = if codec != Codec::None.to_column() {
+                    // Compressed blobs are hashed as they're decompressed. Failing to decompress
+                    // means the file is corrupt.
+                    let mut encoder = OutboardEncoder::new();
+                    let decoded = Codec::from_column(codec).and_then(|codec| {
+                        let mut decoder = compress::decoder(fs::File::open(&path)?, codec)?;
+                        Ok(std::io::copy(&mut decoder, &mut encoder)?)
+                    });
+                    if decoded.is_err() {
+                        return Ok((blob_id, Some(None)));
+                    }
+                    encoder.finalize()
+                } else {
+                    outboard::encode_file(&path, fs::metadata(&path)?.len())?
+                };
+                let outboard_hash = (root == blob_id).then(|| blake3::hash(&outboard));
+                Ok((blob_id, Some(outboard_hash)))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        for (blob_id, result) in large_results {
+            match result {
+                None => report.missing_blob_files.push(blob_id),
+                Some(None) => report.corrupt_blobs.push(blob_id),
+                Some(Some(outboard_hash)) => rebuilt_outboards.push((blob_id, outboard_hash)),
+            }
+        }
+        for (blob_id, outboard_hash) in rebuilt_outboards {
+            let stored: Option<Vec<u8>> = tx
+                .query_row(
+                    "SELECT data FROM outboards WHERE blob_id = ?",
+                    (blob_id.as_bytes(),),
+                    |row| row.get(0),
+                )
+                .optional()?;
+            if stored.is_some_and(|stored| blake3::hash(&stored) != outboard_hash) {
+                report.corrupt_outboards.push(blob_id);
+            }
+        }
+
+        // Check trees, by rebuilding each one from its rows.
+        {
+            let mut query = tx.prepare(
+                "SELECT tree_id, child_name, child_id, node_type, executable FROM trees
+                 ORDER BY tree_id",
+            )?;
+            let mut rows = query.query(())?;
+            let mut current: Option<(blake3::Hash, Tree)> = None;
+            while let Some(row) = rows.next()? {
+                let tree_id: blake3::Hash = row.get::<_, [u8; 32]>(0)?.into();
+                let child_name: String = row.get(1)?;
+                let child_id: blake3::Hash = row.get::<_, [u8; 32]>(2)?.into();
+                let node_type = parse_node_type(row.get(3)?, row.get(4)?);
+                if current.as_ref().is_none_or(|(id, _)| *id != tree_id) {
+                    if let Some((id, tree)) = current.take()
+                        && tree.id() != id
+                    {
+                        report.corrupt_trees.push(id);
+                    }
+                    current = Some((tree_id, Tree::new()));
+                }
+                let tree = &mut current.as_mut().unwrap().1;
+                // Rows that can't be part of a valid tree at all make the tree corrupt.
+                let valid_name = !child_name.is_empty()
+                    && !child_name.contains('/')
+                    && !child_name.contains('\0');
+                match node_type {
+                    Ok(node_type) if valid_name => tree.add_child(child_name, &child_id, node_type),
+                    _ => {
+                        report.corrupt_trees.push(tree_id);
+                        current = None;
+                    }
+                }
+            }
+            if let Some((id, tree)) = current
+                && tree.id() != id
+            {
+                report.corrupt_trees.push(id);
+            }
+            report.corrupt_trees.dedup();
+        }
+
+        // Check for dangling children. Blobs and symlinks point to blobs, and the empty tree
+        // implicitly always exists.
+        {
+            let mut query = tx.prepare(
+                "SELECT tree_id, child_name, child_id FROM trees
+                 WHERE node_type IN (0, 2) AND child_id NOT IN (SELECT blob_id FROM blobs)
+                 UNION ALL
+                 SELECT tree_id, child_name, child_id FROM trees
+                 WHERE node_type = 1 AND child_id != ?
+                     AND child_id NOT IN (SELECT tree_id FROM trees)
+                 ORDER BY tree_id, child_name",
+            )?;
+            let rows = query.query_map((Tree::new().id().as_bytes(),), |row| {
+                let tree_id: [u8; 32] = row.get(0)?;
+                let child_name: String = row.get(1)?;
+                let child_id: [u8; 32] = row.get(2)?;
+                Ok((tree_id.into(), child_name, child_id.into()))
+            })?;
+            for row in rows {
+                report.dangling_children.push(row?);
+            }
+        }
+
+        // Check for orphaned files. Only blob names count. Tempfiles from BlobWriter belong to
+        // writers that haven't taken the lock yet, and `.garbage` files are gc()'s to delete.
+        let large_blob_names: HashSet<String> = large_blobs
+            .iter()
+            .map(|(id, _)| id.to_hex().to_string())
+            .collect();
+        for entry in fs::read_dir(blobs_dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if is_blob_file_name(&name) && !large_blob_names.contains(name.as_ref()) {
+                report.orphaned_files.push(entry.path());
+            }
+        }
+        report.orphaned_files.sort();
+
+        if repair && !report.is_ok() {
+            for blob_id in report
+                .corrupt_blobs
+                .iter()
+                .chain(&report.missing_blob_files)
+            {
+                let path = blob_path(blob_id);
+                if path.exists() {
+                    move_to_quarantine(&path, &quarantine_dir)?;
+                } else if let Some(data) = tx.query_row(
+                    "SELECT data FROM blobs WHERE blob_id = ?",
+                    (blob_id.as_bytes(),),
+                    |row| row.get::<_, Option<Vec<u8>>>(0),
+                )? {
+                    // Keep the bad data from the blobs table too.
+                    let destination = quarantine_path(&quarantine_dir, blob_id.to_hex().as_str())?;
+                    fs::write(destination, data)?;
+                }
+                tx.execute("DELETE FROM blobs WHERE blob_id = ?", (blob_id.as_bytes(),))?;
+                // Any chunks that are no longer used are left for gc().
+                tx.execute(
+                    "DELETE FROM blob_chunks WHERE blob_id = ?",
+                    (blob_id.as_bytes(),),
+                )?;
+                tx.execute(
+                    "DELETE FROM outboards WHERE blob_id = ?",
+                    (blob_id.as_bytes(),),
+                )?;
+            }
+            // read_range() recomputes these the next time it needs them.
+            for blob_id in &report.corrupt_outboards {
+                tx.execute(
+                    "DELETE FROM outboards WHERE blob_id = ?",
+                    (blob_id.as_bytes(),),
+                )?;
+            }
+            for tree_id in &report.corrupt_trees {
+                tx.execute("DELETE FROM trees WHERE tree_id = ?", (tree_id.as_bytes(),))?;
+            }
+            for path in &report.orphaned_files {
+                move_to_quarantine(path, &quarantine_dir)?;
+            }
+            report.quarantine_dir = Some(quarantine_dir);
+            tx.commit()?;
+        }
+        Ok(report)
+    }
+}
@@ -0,0 +1,207 @@
+use crate::{NodeType, Tree, TreeDb, parse_node_type};
+use anyhow::bail;
+use std::collections::HashMap;
+
+/// One entry yielded by [`Walk`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WalkEntry {
+    /// Relative to the root of the walk and separated by `/`.
+    pub path: String,
+    pub id: blake3::Hash,
+    pub node_type: NodeType,
+}
+
+type Children = Vec<(String, blake3::Hash, NodeType)>;
+
+type PruneFn<'a> = Box<dyn FnMut(&WalkEntry) -> bool + 'a>;
+
+// SQLite's default limit on the number of parameters in a query is 32766 since 3.32, but older
+// versions only allow 999.
+const MAX_BATCH: usize = 500;
+
+// Fetches the children of all the given trees, sorted by name, with one query per MAX_BATCH trees.
+fn fetch_children(
+    conn: &rusqlite::Connection,
+    tree_ids: &[blake3::Hash],
+) -> anyhow::Result<HashMap<blake3::Hash, Children>> {
+    let mut fetched: HashMap<blake3::Hash, Children> = HashMap::new();
+    for batch in tree_ids.chunks(MAX_BATCH) {
+        let placeholders = vec!["?"; batch.len()].join(", ");
+        let mut query = conn.prepare(&format!(
+            "SELECT tree_id, child_name, child_id, node_type, executable FROM trees
+             WHERE tree_id IN ({placeholders}) ORDER BY tree_id, child_name"
+        ))?;
+        let params: Vec<&[u8; 32]> = batch.iter().map(|id| id.as_bytes()).collect();
+        let mut rows = query.query(rusqlite::params_from_iter(params))?;
+        while let Some(row) = rows.next()? {
+            let tree_id: [u8; 32] = row.get(0)?;
+            let child_name: String = row.get(1)?;
+            let child_id: [u8; 32] = row.get(2)?;
+            let node_type = parse_node_type(row.get(3)?, row.get(4)?)?;
+            fetched.entry(tree_id.into()).or_default().push((
+                child_name,
+                child_id.into(),
+                node_type,
+            ));
+        }
+    }
+    // Trees with no rows are either the empty tree or unknown.
+    let empty_id = Tree::new().id();
+    for tree_id in tree_ids {
+        if !fetched.contains_key(tree_id) {
+            if *tree_id != empty_id {
+                bail!("tree {} doesn't exist", tree_id);
+            }
+            fetched.insert(*tree_id, Vec::new());
+        }
+    }
+    Ok(fetched)
+}
+
+struct Frame {
+    prefix: String,
+    children: std::vec::IntoIter<(String, blake3::Hash, NodeType)>,
+    // In post-order, the entry for this tree is yielded after its children.
+    post_order_entry: Option<WalkEntry>,
+}
+
+/// A depth-first iterator over every entry under a stored tree, returned by [`TreeDb::walk`].
+/// Entries within a tree are yielded in sorted order. The root itself isn't yielded.
+///
+/// Whenever the walk descends into a tree, it fetches the children of all that tree's subtrees in
+/// a single query.
+pub struct Walk<'a> {
+    conn: &'a rusqlite::Connection,
+    stack: Vec<Frame>,
+    // Children of trees that have been prefetched but not yet visited.
+    prefetched: HashMap<blake3::Hash, Children>,
+    post_order: bool,
+    prune: Option<PruneFn<'a>>,
+}
+
+impl std::fmt::Debug for Walk<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Walk")
+            .field("depth", &self.stack.len())
+            .field("post_order", &self.post_order)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a> Walk<'a> {
+    /// Yield each tree after its children instead of before.
+    pub fn post_order(mut self, post_order: bool) -> Self {
+        self.post_order = post_order;
+        self
+    }
+
+    /// Don't descend into trees for which `prune` returns true. The trees themselves are still
+    /// yielded.
+    pub fn prune(mut self, prune: impl FnMut(&WalkEntry) -> bool + 'a) -> Self {
+        self.prune = Some(Box::new(prune));
+        self
+    }
+
+    fn push_frame(
+        &mut self,
+        tree_id: &blake3::Hash,
+        prefix: String,
+        post_order_entry: Option<WalkEntry>,
+    ) -> anyhow::Result<()> {
+        let children = match self.prefetched.remove(tree_id) {
+            Some(children) => children,
+            None => fetch_children(self.conn, &[*tree_id])?
+                .remove(tree_id)
+                .expect("fetched"),
+        };
+        let mut subtrees: Vec<blake3::Hash> = children
+            .iter()
+            .filter(|(_, id, node_type)| {
+                *node_type == NodeType::Tree && !self.prefetched.contains_key(id)
+            })
+            .map(|(_, id, _)| *id)
+            .collect();
+        subtrees.sort_unstable_by_key(|id| *id.as_bytes());
+        subtrees.dedup();
+        if !subtrees.is_empty() {
+            self.prefetched
+                .extend(fetch_children(self.conn, &subtrees)?);
+        }
+        self.stack.push(Frame {
+            prefix,
+            children: children.into_iter(),
+            post_order_entry,
+        });
+        Ok(())
+    }
+
+    fn next_inner(&mut self) -> anyhow::Result<Option<WalkEntry>> {
+        loop {
+            let Some(frame) = self.stack.last_mut() else {
+                return Ok(None);
+            };
+            let Some((name, id, node_type)) = frame.children.next() else {
+                let frame = self.stack.pop().unwrap();
+                if let Some(entry) = frame.post_order_entry {
+                    return Ok(Some(entry));
+                }
+                continue;
+            };
+            let path = if frame.prefix.is_empty() {
+                name
+            } else {
+                format!("{}/{}", frame.prefix, name)
+            };
+            let entry = WalkEntry {
+                path,
+                id,
+                node_type,
+            };
+            if node_type != NodeType::Tree {
+                return Ok(Some(entry));
+            }
+            let pruned = match &mut self.prune {
+                Some(prune) => prune(&entry),
+                None => false,
+            };
+            if pruned {
+                return Ok(Some(entry));
+            }
+            if self.post_order {
+                self.push_frame(&id, entry.path.clone(), Some(entry))?;
+            } else {
+                self.push_frame(&id, entry.path.clone(), None)?;
+                return Ok(Some(entry));
+            }
+        }
+    }
+}
+
+impl Iterator for Walk<'_> {
+    type Item = anyhow::Result<WalkEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = self.next_inner();
+        if result.is_err() {
+            // Don't keep going after an error.
+            self.stack.clear();
+        }
+        result.transpose()
+    }
+}
+
+impl TreeDb {
+    /// Returns a depth-first [`Walk`] over every entry under the tree `tree_id`, in pre-order by
+    /// default.
+    pub fn walk(&self, tree_id: &blake3::Hash) -> anyhow::Result<Walk<'_>> {
+        let mut walk = Walk {
+            conn: &self.conn,
+            stack: Vec::new(),
+            prefetched: HashMap::new(),
+            post_order: false,
+            prune: None,
+        };
+        walk.push_frame(tree_id, String::new(), None)?;
+        Ok(walk)
+    }
+}